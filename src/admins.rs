@@ -0,0 +1,34 @@
+use std::collections::HashSet;
+
+use teloxide::types::UserId;
+
+// Comma separated Telegram user IDs of the people who run the instance.
+const ADMINS_VARIABLE: &str = "TELEPIRATE_ADMINS";
+
+lazy_static::lazy_static! {
+    static ref ADMINS: HashSet<UserId> = load();
+}
+
+pub fn is_admin(user_id: UserId) -> bool {
+    ADMINS.contains(&user_id)
+}
+
+#[tracing::instrument]
+fn load() -> HashSet<UserId> {
+    let admins: HashSet<UserId> = std::env::var(ADMINS_VARIABLE)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .filter_map(|id| {
+            id.parse()
+                .map(UserId)
+                .inspect_err(|_| warn!("Ignoring invalid admin ID '{id}'."))
+                .ok()
+        })
+        .collect();
+    if admins.is_empty() {
+        debug!("No admins configured, admin features are disabled.");
+    }
+    admins
+}
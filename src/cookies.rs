@@ -0,0 +1,294 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use humantime::format_rfc3339_seconds as timestamp;
+use teloxide::types::UserId;
+use url::Url;
+
+use crate::i18n;
+
+// Jars are kept in the mounted cookies directory, so that they survive container rebuilds.
+const SHARED_JARS_DIRECTORY: &str = "/app/cookies/jars";
+// Jars uploaded by users for their own accounts, in a subdirectory per user ID. They are never used for other users.
+const PRIVATE_JARS_DIRECTORY: &str = "/app/cookies/users";
+// The single jar from before per-domain jars existed, used for URLs that no other jar matches.
+const LEGACY_JAR: &str = "/app/cookies/cookies.txt";
+// Real cookie exports are a few kilobytes, anything much larger is not a cookie file.
+pub const MAX_COOKIES_FILE_SIZE: u32 = 1_000_000;
+// Browsers export HttpOnly cookies as lines with this prefix, they are cookies rather than comments.
+const HTTP_ONLY_PREFIX: &str = "#HttpOnly_";
+const NETSCAPE_HEADER: &str = "# Netscape HTTP Cookie File";
+
+// Only the fields that are needed to describe a jar and to match it against URLs.
+struct Cookie {
+    domain: String,
+    // Unix time, 0 for session cookies.
+    expires: u64,
+}
+
+// Cookies of a domain in a jar.
+struct DomainSummary {
+    count: usize,
+    expires: u64,
+}
+
+// Contents of a Netscape formatted cookies.txt file, as exported by browser extensions.
+pub struct CookieJar {
+    cookies: Vec<Cookie>,
+    content: String,
+}
+
+impl CookieJar {
+    // Every line that is not a comment must have 7 tab separated fields:
+    // domain, include subdomains, path, secure, expiry, name and value. Errors are in the language given.
+    pub fn parse(content: &str, language: &str) -> Result<Self, String> {
+        let mut cookies = Vec::new();
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            let line = line.strip_prefix(HTTP_ONLY_PREFIX).unwrap_or(line);
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let line_number = index + 1;
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != 7 {
+                return Err(i18n::text_with(
+                    language,
+                    "cookies_error.field_count",
+                    &[("line", &line_number), ("count", &fields.len())],
+                ));
+            }
+            let domain = fields[0].trim_start_matches('.').to_ascii_lowercase();
+            let is_valid_domain = !domain.is_empty()
+                && domain
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+            if !is_valid_domain {
+                return Err(i18n::text_with(
+                    language,
+                    "cookies_error.domain",
+                    &[("line", &line_number), ("domain", &fields[0])],
+                ));
+            }
+            for (position, key) in [
+                (1, "cookies_error.include_subdomains_flag"),
+                (3, "cookies_error.secure_flag"),
+            ] {
+                if !["TRUE", "FALSE"].contains(&fields[position].to_ascii_uppercase().as_str()) {
+                    return Err(i18n::text_with(language, key, &[("line", &line_number)]));
+                }
+            }
+            // Some exporters write fractional expiry dates.
+            let expires = fields[4]
+                .split('.')
+                .next()
+                .and_then(|expires| expires.parse::<u64>().ok())
+                .ok_or_else(|| {
+                    i18n::text_with(language, "cookies_error.expiry", &[("line", &line_number)])
+                })?;
+            cookies.push(Cookie { domain, expires });
+        }
+        if cookies.is_empty() {
+            return Err(i18n::text(language, "cookies_error.empty"));
+        }
+        // yt-dlp expects the header on the first line.
+        let mut content = content.replace("\r\n", "\n");
+        if !content.starts_with(NETSCAPE_HEADER) {
+            content = format!("{NETSCAPE_HEADER}\n{content}");
+        }
+        Ok(Self { cookies, content })
+    }
+
+    fn domains(&self) -> BTreeMap<&str, DomainSummary> {
+        let mut domains: BTreeMap<&str, DomainSummary> = BTreeMap::new();
+        for cookie in &self.cookies {
+            let summary = domains.entry(&cookie.domain).or_insert(DomainSummary {
+                count: 0,
+                expires: 0,
+            });
+            summary.count += 1;
+            summary.expires = summary.expires.max(cookie.expires);
+        }
+        domains
+    }
+
+    // The jar is named after the domain with the most cookies, a new upload for it replaces the old one.
+    pub fn primary_domain(&self) -> String {
+        self.domains()
+            .into_iter()
+            .max_by_key(|(_, summary)| summary.count)
+            .map(|(domain, _)| domain.to_string())
+            .unwrap_or_default()
+    }
+
+    // Length of the most specific cookie domain that covers the host, None if no cookie does.
+    fn specificity(&self, host: &str) -> Option<usize> {
+        self.cookies
+            .iter()
+            .filter(|cookie| {
+                host == cookie.domain || host.ends_with(&format!(".{}", cookie.domain))
+            })
+            .map(|cookie| cookie.domain.len())
+            .max()
+    }
+
+    // One line per domain with the number of cookies and when the last of them expires.
+    pub fn describe(&self, language: &str) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        let domains = self.domains();
+        let mut lines = vec![i18n::text_with(
+            language,
+            "cookies_summary",
+            &[("count", &domains.len())],
+        )];
+        for (domain, summary) in domains {
+            let expiry = match summary.expires {
+                0 => i18n::text(language, "cookies_session_only"),
+                expires => {
+                    let date = timestamp(UNIX_EPOCH + Duration::from_secs(expires)).to_string();
+                    let date = date.split('T').next().unwrap_or_default().to_string();
+                    let key = if expires < now {
+                        "cookies_expired"
+                    } else {
+                        "cookies_expire"
+                    };
+                    i18n::text_with(language, key, &[("date", &date)])
+                }
+            };
+            lines.push(i18n::text_with(
+                language,
+                "cookies_domain",
+                &[
+                    ("domain", &domain),
+                    ("count", &summary.count),
+                    ("expiry", &expiry),
+                ],
+            ));
+        }
+        lines.join("\n")
+    }
+
+    // Saves the jar among the shared ones and returns its path.
+    pub fn save_shared(&self) -> Result<PathBuf, std::io::Error> {
+        self.save_in(Path::new(SHARED_JARS_DIRECTORY))
+    }
+
+    // Saves the jar so that only tasks of the user can use it, and returns its path.
+    pub fn save_private(&self, user_id: UserId) -> Result<PathBuf, std::io::Error> {
+        self.save_in(&private_directory(user_id))
+    }
+
+    fn save_in(&self, directory: &Path) -> Result<PathBuf, std::io::Error> {
+        std::fs::create_dir_all(directory)?;
+        let path = directory.join(format!("{}.txt", self.primary_domain()));
+        std::fs::write(&path, &self.content)?;
+        Ok(path)
+    }
+}
+
+fn private_directory(user_id: UserId) -> PathBuf {
+    Path::new(PRIVATE_JARS_DIRECTORY).join(user_id.to_string())
+}
+
+// Removes all private jars of the user, returns how many there were.
+#[tracing::instrument]
+pub fn delete_private(user_id: UserId) -> Result<usize, std::io::Error> {
+    let directory = private_directory(user_id);
+    if !directory.exists() {
+        return Ok(0);
+    }
+    let count = std::fs::read_dir(&directory)?.count();
+    std::fs::remove_dir_all(&directory)?;
+    Ok(count)
+}
+
+// Picks the jar whose cookies cover the host of the URL most specifically. Private jars of the user
+// come first, then the shared ones, then the legacy jar.
+#[tracing::instrument(skip(url))]
+pub fn jar_for(url: &Url, user_id: Option<UserId>) -> Option<PathBuf> {
+    let host = url.host_str()?.to_ascii_lowercase();
+    let private_match = user_id.and_then(|user_id| best_match(&private_directory(user_id), &host));
+    let jar = private_match
+        .or_else(|| best_match(Path::new(SHARED_JARS_DIRECTORY), &host))
+        .or_else(|| Some(PathBuf::from(LEGACY_JAR)).filter(|path| path.exists()));
+    if let Some(jar) = &jar {
+        debug!("Using cookies from {} ...", jar.display());
+    }
+    jar
+}
+
+fn best_match(directory: &Path, host: &str) -> Option<PathBuf> {
+    jars_in(directory)
+        .into_iter()
+        .filter_map(|(path, jar)| jar.specificity(host).map(|specificity| (specificity, path)))
+        .max_by_key(|(specificity, _)| *specificity)
+        .map(|(_, path)| path)
+}
+
+// Unreadable or broken files are skipped, so that one bad jar doesn't break downloads.
+fn jars_in(directory: &Path) -> Vec<(PathBuf, CookieJar)> {
+    std::fs::read_dir(directory)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "txt"))
+        .filter_map(|path| {
+            let jar = std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|content| CookieJar::parse(&content, i18n::DEFAULT_LANGUAGE))
+                .inspect_err(|e| warn!("Skipping cookie jar {}: {e}", path.display()))
+                .ok()?;
+            Some((path, jar))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Result<CookieJar, String> {
+        CookieJar::parse(content, i18n::DEFAULT_LANGUAGE)
+    }
+
+    #[test]
+    fn parses_netscape_jars() {
+        let content = "# Netscape HTTP Cookie File\r\n\
+            .YouTube.com\tTRUE\t/\tTRUE\t1900000000.5\tSID\tvalue\r\n\
+            #HttpOnly_.youtube.com\tTRUE\t/\tTRUE\t0\tHSID\tvalue\r\n\
+            \r\n\
+            # comment\r\n";
+        let jar = parse(content).unwrap();
+        assert_eq!(jar.cookies.len(), 2);
+        assert_eq!(jar.cookies[0].domain, "youtube.com");
+        assert_eq!(jar.cookies[0].expires, 1_900_000_000);
+        assert_eq!(jar.primary_domain(), "youtube.com");
+        assert!(!jar.content.contains('\r'));
+    }
+
+    #[test]
+    fn adds_the_header() {
+        let jar = parse("example.com\tFALSE\t/\tFALSE\t0\tname\tvalue").unwrap();
+        assert!(jar.content.starts_with(NETSCAPE_HEADER));
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        assert!(parse("example.com\tFALSE\t/\tFALSE\t0\tname").is_err());
+        assert!(parse("exa mple.com\tFALSE\t/\tFALSE\t0\tname\tvalue").is_err());
+        assert!(parse("example.com\tYES\t/\tFALSE\t0\tname\tvalue").is_err());
+        assert!(parse("example.com\tFALSE\t/\tNO\t0\tname\tvalue").is_err());
+        assert!(parse("example.com\tFALSE\t/\tFALSE\tnever\tname\tvalue").is_err());
+    }
+
+    #[test]
+    fn rejects_jars_without_cookies() {
+        assert!(parse("").is_err());
+        assert!(parse("# Netscape HTTP Cookie File\n# nothing here\n").is_err());
+    }
+}
@@ -0,0 +1,286 @@
+use std::error::Error;
+use std::fmt;
+
+use reqwest::StatusCode;
+
+use super::INACTIVITY_TIMEOUT_SECS;
+use crate::i18n;
+
+// Why a download failed, as far as it can be told from the output of the downloader.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DownloadErrorKind {
+    UnsupportedUrl,
+    // Private, deleted or otherwise gone.
+    Unavailable,
+    GeoBlocked,
+    SignInRequired,
+    // HTTP 429.
+    RateLimited,
+    Network,
+    DiskFull,
+    TooLarge,
+    // ffmpeg failed to convert a finished file.
+    Conversion,
+    Cancelled,
+    Timeout,
+    Unknown,
+}
+
+// Lowercase fragments of yt-dlp and gallery-dl errors. The first kind with a matching fragment wins,
+// e.g. "Private video. Sign in if you've been granted access" is about a private video, not about signing in.
+const PATTERNS: [(DownloadErrorKind, &[&str]); 8] = [
+    (
+        DownloadErrorKind::RateLimited,
+        &[
+            "http error 429",
+            "too many requests",
+            "rate-limit",
+            "rate limit",
+        ],
+    ),
+    (
+        DownloadErrorKind::GeoBlocked,
+        &[
+            "not available in your country",
+            "geo restriction",
+            "geo-restricted",
+            "georestricted",
+            "in your country",
+        ],
+    ),
+    (
+        DownloadErrorKind::Unavailable,
+        &[
+            "private video",
+            "video unavailable",
+            "this video is private",
+            "has been removed",
+            "been terminated",
+            "no longer available",
+            "does not exist",
+            "http error 404",
+            "http error 410",
+            "notfounderror",
+        ],
+    ),
+    (
+        DownloadErrorKind::SignInRequired,
+        &[
+            "sign in to confirm",
+            "login required",
+            "log in to",
+            "requires authentication",
+            "registered users",
+            "--cookies",
+            "http error 401",
+            "http error 403",
+            "authorizationerror",
+            "authenticationerror",
+        ],
+    ),
+    (
+        DownloadErrorKind::DiskFull,
+        &["no space left on device", "errno 28"],
+    ),
+    (
+        DownloadErrorKind::Network,
+        &[
+            "unable to download webpage",
+            "error sending request",
+            "connection reset",
+            "connection refused",
+            "connection aborted",
+            "remote end closed connection",
+            "timed out",
+            "name or service not known",
+            "temporary failure in name resolution",
+            "network is unreachable",
+            "proxy",
+        ],
+    ),
+    (
+        DownloadErrorKind::UnsupportedUrl,
+        &[
+            "unsupported url",
+            "no suitable extractor",
+            "is not a valid url",
+        ],
+    ),
+    (
+        DownloadErrorKind::TooLarge,
+        &["over the size limit", "larger than max-filesize"],
+    ),
+];
+
+#[derive(Debug)]
+pub struct DownloadError {
+    pub kind: DownloadErrorKind,
+    // Raw output of the downloader, for the logs and for admins.
+    pub log: String,
+}
+
+impl DownloadError {
+    pub fn new(kind: DownloadErrorKind) -> Self {
+        Self {
+            kind,
+            log: String::new(),
+        }
+    }
+
+    pub fn with_log(kind: DownloadErrorKind, log: String) -> Self {
+        Self { kind, log }
+    }
+
+    // Warnings mention all kinds of things that didn't stop the download, e.g. a failed request for subtitles,
+    // thus only the error lines are looked at. Logs without them are looked at as a whole, except for warnings.
+    pub fn classify(log: &str) -> Self {
+        let errors: Vec<&str> = log.lines().filter(|line| is_error_line(line)).collect();
+        let relevant_lines = if errors.is_empty() {
+            log.lines().filter(|line| !is_warning_line(line)).collect()
+        } else {
+            errors
+        };
+        let lowercase_log = relevant_lines.join("\n").to_lowercase();
+        let kind = PATTERNS
+            .iter()
+            .find(|(_, fragments)| {
+                fragments
+                    .iter()
+                    .any(|fragment| lowercase_log.contains(fragment))
+            })
+            .map(|(kind, _)| *kind)
+            .unwrap_or(DownloadErrorKind::Unknown);
+        Self {
+            kind,
+            log: log.to_string(),
+        }
+    }
+
+    // Errors of the downloaders themselves, e.g. failed requests of the HTTP downloader.
+    pub fn from_error(error: Box<dyn Error + Send + Sync>) -> Self {
+        let error = match error.downcast::<Self>() {
+            Ok(download_error) => return *download_error,
+            Err(error) => error,
+        };
+        let log = error.to_string();
+        if let Some(request_error) = error.downcast_ref::<reqwest::Error>() {
+            let kind = match request_error.status() {
+                Some(StatusCode::TOO_MANY_REQUESTS) => DownloadErrorKind::RateLimited,
+                Some(StatusCode::NOT_FOUND | StatusCode::GONE) => DownloadErrorKind::Unavailable,
+                Some(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) => {
+                    DownloadErrorKind::SignInRequired
+                }
+                Some(StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS) => DownloadErrorKind::GeoBlocked,
+                _ if request_error.is_connect() || request_error.is_timeout() => {
+                    DownloadErrorKind::Network
+                }
+                _ => return Self::classify(&log),
+            };
+            return Self { kind, log };
+        }
+        Self::classify(&log)
+    }
+
+    // The message for the user in their language.
+    pub fn message(&self, language: &str) -> String {
+        let key = match self.kind {
+            DownloadErrorKind::UnsupportedUrl => "download_error.unsupported_url",
+            DownloadErrorKind::Unavailable => "download_error.unavailable",
+            DownloadErrorKind::GeoBlocked => "download_error.geo_blocked",
+            DownloadErrorKind::SignInRequired => "download_error.sign_in_required",
+            DownloadErrorKind::RateLimited => "download_error.rate_limited",
+            DownloadErrorKind::Network => "download_error.network",
+            DownloadErrorKind::DiskFull => "download_error.disk_full",
+            DownloadErrorKind::TooLarge => "download_error.too_large",
+            DownloadErrorKind::Conversion => "download_error.conversion",
+            DownloadErrorKind::Cancelled => "download_error.cancelled",
+            DownloadErrorKind::Timeout => {
+                let minutes = INACTIVITY_TIMEOUT_SECS / 60;
+                return i18n::text_with(
+                    language,
+                    "download_error.timeout",
+                    &[("minutes", &minutes)],
+                );
+            }
+            DownloadErrorKind::Unknown => "download_error.unknown",
+        };
+        i18n::text(language, key)
+    }
+
+    // Whether trying again later can help.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.kind,
+            DownloadErrorKind::RateLimited | DownloadErrorKind::Network
+        )
+    }
+}
+
+// yt-dlp starts errors with "ERROR:", gallery-dl tags them as "[extractor][error]".
+fn is_error_line(line: &str) -> bool {
+    line.trim_start().starts_with("ERROR:") || line.contains("][error]")
+}
+
+fn is_warning_line(line: &str) -> bool {
+    line.trim_start().starts_with("WARNING:") || line.contains("][warning]")
+}
+
+// The message for the user, in English for the logs.
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message(i18n::DEFAULT_LANGUAGE))
+    }
+}
+
+impl Error for DownloadError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kind(log: &str) -> DownloadErrorKind {
+        DownloadError::classify(log).kind
+    }
+
+    #[test]
+    fn warnings_are_ignored_when_there_are_errors() {
+        let log = "WARNING: [youtube] Unable to download subtitles: HTTP Error 429: Too Many Requests\n\
+            ERROR: [youtube] abc: Private video. Sign in if you've been granted access to this video";
+        assert_eq!(kind(log), DownloadErrorKind::Unavailable);
+    }
+
+    #[test]
+    fn warnings_alone_are_not_classified() {
+        let log =
+            "WARNING: [youtube] Unable to download subtitles: HTTP Error 429: Too Many Requests";
+        assert_eq!(kind(log), DownloadErrorKind::Unknown);
+    }
+
+    #[test]
+    fn logs_without_error_lines_are_classified_as_a_whole() {
+        let log = "WARNING: something else\nOSError: [Errno 28] No space left on device";
+        assert_eq!(kind(log), DownloadErrorKind::DiskFull);
+    }
+
+    #[test]
+    fn gallery_dl_errors_are_classified() {
+        assert_eq!(
+            kind("[twitter][error] AuthorizationError: Login required"),
+            DownloadErrorKind::SignInRequired
+        );
+        assert_eq!(
+            kind(
+                "[instagram][warning] HTTP Error 429\n[instagram][error] NotFoundError: Requested post"
+            ),
+            DownloadErrorKind::Unavailable
+        );
+    }
+
+    #[test]
+    fn the_log_is_kept() {
+        let log = "ERROR: Unsupported URL: https://example.com";
+        let error = DownloadError::classify(log);
+        assert_eq!(error.kind, DownloadErrorKind::UnsupportedUrl);
+        assert_eq!(error.log, log);
+    }
+}
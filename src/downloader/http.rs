@@ -0,0 +1,382 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use percent_encoding::percent_decode_str;
+use reqwest::header::{CONTENT_DISPOSITION, CONTENT_TYPE, HeaderMap, RANGE};
+use reqwest::{Client, Proxy, StatusCode};
+use tokio::io::AsyncWriteExt;
+use url::Url;
+
+use super::error::{DownloadError, DownloadErrorKind};
+use super::{DownloadJob, Downloader, INACTIVITY_TIMEOUT_SECS};
+use crate::misc::sleep;
+use crate::profiles::MediaProfile;
+use crate::proxies;
+use crate::settings::{OversizePolicy, UserSettings};
+use crate::task::fileinfo::FileInfo;
+use crate::task::progress::DownloadProgress;
+use crate::task::timerange::TimeRange;
+
+const USER_AGENT: &str = concat!("telepirate/", env!("CARGO_PKG_VERSION"));
+const PROBE_TIMEOUT_SECS: u64 = 15;
+const CONNECT_TIMEOUT_SECS: u64 = 30;
+// Dropped connections are resumed from where they stopped with Range requests.
+const RESUME_ATTEMPTS: u32 = 5;
+const RESUME_DELAY_SECS: u32 = 5;
+const FALLBACK_FILE_NAME: &str = "download";
+
+// Web pages are left to yt-dlp, which finds the media embedded in them.
+const PAGE_MIME_TYPES: [&str; 2] = ["text/html", "application/xhtml+xml"];
+// Extensions for file names that have none.
+const EXTENSIONS_BY_MIME_TYPE: [(&str, &str); 18] = [
+    ("audio/mpeg", "mp3"),
+    ("audio/mp4", "m4a"),
+    ("audio/ogg", "ogg"),
+    ("audio/opus", "opus"),
+    ("audio/flac", "flac"),
+    ("audio/wav", "wav"),
+    ("video/mp4", "mp4"),
+    ("video/webm", "webm"),
+    ("video/quicktime", "mov"),
+    ("video/x-matroska", "mkv"),
+    ("image/jpeg", "jpg"),
+    ("image/png", "png"),
+    ("image/webp", "webp"),
+    ("image/gif", "gif"),
+    ("application/pdf", "pdf"),
+    ("application/zip", "zip"),
+    ("application/epub+zip", "epub"),
+    ("text/plain", "txt"),
+];
+
+// Downloads direct links to files without external programs.
+pub struct HttpDownloader {
+    client: Client,
+    profile: &'static MediaProfile,
+    url: Url,
+    // Clips need yt-dlp, a file can't be cut while downloading.
+    time_range: Option<TimeRange>,
+    // Files over the limit are not downloaded if they are going to be skipped anyway.
+    max_size: Option<u64>,
+    proxy: Option<Url>,
+}
+
+// How a single request ended.
+enum Fetched {
+    Complete,
+    TooLarge(u64),
+}
+
+impl HttpDownloader {
+    pub fn new(
+        profile: &'static MediaProfile,
+        url: Url,
+        time_range: Option<TimeRange>,
+        settings: &UserSettings,
+        proxy: Option<Url>,
+    ) -> Result<Self, reqwest::Error> {
+        let mut client = Client::builder()
+            .user_agent(USER_AGENT)
+            .connect_timeout(Duration::from_secs(CONNECT_TIMEOUT_SECS))
+            // A stalled connection is resumed like a dropped one.
+            .read_timeout(Duration::from_secs(INACTIVITY_TIMEOUT_SECS));
+        if let Some(proxy) = &proxy {
+            client = client.proxy(Proxy::all(proxy.as_str())?);
+        }
+        let client = client.build()?;
+        let max_size =
+            (settings.oversize_policy == OversizePolicy::Skip).then(|| settings.size_limit_bytes());
+        Ok(Self {
+            client,
+            profile,
+            url,
+            time_range,
+            max_size,
+            proxy,
+        })
+    }
+
+    // Requests the rest of the file, appending to what is already downloaded into path.
+    async fn fetch(
+        &self,
+        path: &mut Option<PathBuf>,
+        job: &DownloadJob,
+    ) -> Result<Fetched, Box<dyn Error + Send + Sync>> {
+        let downloaded = path
+            .as_ref()
+            .and_then(|path| path.metadata().ok())
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        let mut request = self.client.get(self.url.clone());
+        if downloaded > 0 {
+            debug!("Resuming from {downloaded} bytes ...");
+            request = request.header(RANGE, format!("bytes={downloaded}-"));
+        }
+        let mut response = request.send().await?.error_for_status()?;
+        // Servers without Range support send the whole file again.
+        let resumed = response.status() == StatusCode::PARTIAL_CONTENT;
+        let offset = if resumed { downloaded } else { 0 };
+        let total = response.content_length().map(|length| length + offset);
+        if let (Some(max_size), Some(total)) = (self.max_size, total)
+            && total >= max_size
+        {
+            return Ok(Fetched::TooLarge(total));
+        }
+        let path = path
+            .get_or_insert_with(|| job.directory.join(file_name(response.headers(), &self.url)))
+            .clone();
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resumed)
+            .truncate(!resumed)
+            .open(&path)
+            .await?;
+
+        let started_at = Instant::now();
+        let mut received: u64 = 0;
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await?;
+            received += chunk.len() as u64;
+            let speed = received as f64 / started_at.elapsed().as_secs_f64().max(0.001);
+            let current = offset + received;
+            *job.progress.lock().unwrap() = DownloadProgress {
+                percent: total
+                    .map(|total| (current as f64 / total.max(1) as f64 * 100.0).min(100.0)),
+                speed: Some(speed),
+                eta: total.map(|total| {
+                    Duration::from_secs(
+                        (total.saturating_sub(current) as f64 / speed.max(1.0)) as u64,
+                    )
+                }),
+                ..Default::default()
+            };
+        }
+        file.flush().await?;
+        Ok(Fetched::Complete)
+    }
+
+    async fn download_with_resume(
+        &self,
+        job: &DownloadJob,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let mut path = None;
+        for attempt in 1..=RESUME_ATTEMPTS {
+            match self.fetch(&mut path, job).await {
+                Ok(Fetched::Complete) => break,
+                Ok(Fetched::TooLarge(size)) => {
+                    return Ok(format!(
+                        "File is {} MB, which is over the size limit.",
+                        size / 1_000_000
+                    ));
+                }
+                Err(e) if attempt < RESUME_ATTEMPTS => {
+                    warn!("Download attempt {attempt}/{RESUME_ATTEMPTS} failed: {e}");
+                    sleep(RESUME_DELAY_SECS).await;
+                }
+                Err(e) => {
+                    let is_connect_error = e
+                        .downcast_ref::<reqwest::Error>()
+                        .is_some_and(|e| e.is_connect());
+                    if let Some(proxy) = &self.proxy
+                        && is_connect_error
+                    {
+                        proxies::report_failure(proxy);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        // The loop only ends without returning once the file is complete.
+        let filepath = path.ok_or("Nothing was downloaded.")?;
+        debug!("Finished: {}", filepath.display());
+        // There is no metadata besides the link itself, the file name stands in for the title.
+        let title = filepath
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned());
+        let file_info = FileInfo {
+            filepath,
+            title,
+            webpage_url: Some(self.url.to_string()),
+            ..Default::default()
+        };
+        // An error means the receiver is gone because sending failed, the task is being stopped anyway.
+        let _ = job.finished_files_tx.send(file_info).await;
+        Ok(String::new())
+    }
+}
+
+impl Downloader for HttpDownloader {
+    // A direct link responds with a file rather than a page, and the file suits the profile.
+    #[tracing::instrument(skip_all)]
+    async fn probe(&self) -> bool {
+        if self.time_range.is_some() {
+            return false;
+        }
+        let response = match self
+            .client
+            .head(self.url.clone())
+            .timeout(Duration::from_secs(PROBE_TIMEOUT_SECS))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+        {
+            Ok(response) => response,
+            Err(e) => {
+                trace!("Not a direct link: {e}");
+                return false;
+            }
+        };
+        let headers = response.headers();
+        match mime_type(headers) {
+            Some(mime_type) if !PAGE_MIME_TYPES.contains(&mime_type.as_str()) => {}
+            _ => return false,
+        }
+        self.profile
+            .matches_extension(Path::new(&file_name(headers, &self.url)))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn download(&self, job: DownloadJob) -> Result<String, Box<dyn Error + Send + Sync>> {
+        debug!("Downloading {} ...", self.url);
+        tokio::fs::create_dir_all(&job.directory).await?;
+        tokio::select! {
+            biased;
+            _ = job.cancellation_token.cancelled() => {
+                Err(DownloadError::new(DownloadErrorKind::Cancelled).into())
+            }
+            result = self.download_with_resume(&job) => result,
+        }
+    }
+}
+
+// MIME type without parameters such as charset.
+fn mime_type(headers: &HeaderMap) -> Option<String> {
+    let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    let essence = content_type.split(';').next()?.trim().to_ascii_lowercase();
+    Some(essence)
+}
+
+// Name from Content-Disposition, then from the URL. An extension is added by MIME type if the name has none.
+fn file_name(headers: &HeaderMap, url: &Url) -> String {
+    let name = headers
+        .get(CONTENT_DISPOSITION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(content_disposition_file_name)
+        .or_else(|| {
+            url.path_segments()
+                .and_then(|mut segments| segments.next_back())
+                .map(|segment| percent_decode_str(segment).decode_utf8_lossy().into_owned())
+        });
+    // Only the last component is kept, so that a name can't point outside the task directory.
+    let mut name = name
+        .as_deref()
+        .and_then(|name| Path::new(name).file_name())
+        .and_then(|name| name.to_str())
+        .map(|name| name.replace('\\', "_"))
+        .filter(|name| !name.trim_matches('.').is_empty())
+        .unwrap_or(FALLBACK_FILE_NAME.to_string());
+    if Path::new(&name).extension().is_none()
+        && let Some(mime_type) = mime_type(headers)
+        && let Some((_, extension)) = EXTENSIONS_BY_MIME_TYPE
+            .iter()
+            .find(|(known_type, _)| *known_type == mime_type)
+    {
+        name = format!("{name}.{extension}");
+    }
+    name
+}
+
+// RFC 6266: filename* holds a percent-encoded name and takes precedence over the plain filename.
+fn content_disposition_file_name(value: &str) -> Option<String> {
+    let mut plain_name = None;
+    for parameter in value.split(';') {
+        let Some((key, value)) = parameter.split_once('=') else {
+            continue;
+        };
+        let value = value.trim();
+        match key.trim().to_ascii_lowercase().as_str() {
+            // Formatted as charset'language'encoded-name.
+            "filename*" => {
+                if let Some(encoded_name) = value.splitn(3, '\'').nth(2) {
+                    return Some(
+                        percent_decode_str(encoded_name)
+                            .decode_utf8_lossy()
+                            .into_owned(),
+                    );
+                }
+            }
+            "filename" => plain_name = Some(value.trim_matches('"').to_string()),
+            _ => {}
+        }
+    }
+    plain_name
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    fn file_name_from(content_disposition: &str, url: &str) -> String {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_DISPOSITION,
+            HeaderValue::from_str(content_disposition).unwrap(),
+        );
+        file_name(&headers, &Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn encoded_name_takes_precedence() {
+        assert_eq!(
+            content_disposition_file_name(
+                "attachment; filename=\"plain.mp4\"; filename*=UTF-8''%D0%B2%D0%B8%D0%B4%D0%B5%D0%BE.mp4"
+            ),
+            Some("видео.mp4".to_string())
+        );
+    }
+
+    #[test]
+    fn plain_names_are_unquoted() {
+        assert_eq!(
+            content_disposition_file_name("attachment; filename=\"my video.mp4\""),
+            Some("my video.mp4".to_string())
+        );
+        assert_eq!(
+            content_disposition_file_name("attachment; FILENAME=video.mp4"),
+            Some("video.mp4".to_string())
+        );
+        assert_eq!(content_disposition_file_name("inline"), None);
+    }
+
+    #[test]
+    fn encoded_names_cannot_leave_the_task_directory() {
+        let url = "https://example.com/file";
+        assert_eq!(
+            file_name_from("attachment; filename*=UTF-8''..%2F..%2Fetc%2Fpasswd", url),
+            "passwd"
+        );
+        assert_eq!(
+            file_name_from("attachment; filename*=UTF-8''%2Fetc%2Fpasswd", url),
+            "passwd"
+        );
+        assert_eq!(
+            file_name_from("attachment; filename*=UTF-8''..%5C..%5Cboot.ini", url),
+            ".._.._boot.ini"
+        );
+        assert_eq!(
+            file_name_from("attachment; filename*=UTF-8''..", url),
+            FALLBACK_FILE_NAME
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_url() {
+        let headers = HeaderMap::new();
+        let url = Url::parse("https://example.com/media/clip%201.mp4?x=1").unwrap();
+        assert_eq!(file_name(&headers, &url), "clip 1.mp4");
+    }
+}
@@ -0,0 +1,94 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use teloxide::types::UserId;
+use tokio::sync::mpsc::Sender;
+use tokio_util::sync::CancellationToken;
+use url::Url;
+
+use crate::profiles::MediaProfile;
+use crate::settings::UserSettings;
+use crate::task::fileinfo::FileInfo;
+use crate::task::progress::SharedProgress;
+use crate::task::timerange::TimeRange;
+
+pub mod error;
+pub mod http;
+pub mod process;
+
+use http::HttpDownloader;
+use process::ProcessDownloader;
+
+// A download that shows no activity for this long is stopped. Time spent waiting for files to be sent
+// doesn't count, so that slow uploads don't cut long playlists short.
+pub const INACTIVITY_TIMEOUT_SECS: u64 = 1800;
+
+// Proxy of the latest download attempt without credentials, so that the task records the one actually used.
+pub type SharedProxy = Arc<Mutex<Option<Url>>>;
+
+// Everything a downloader needs to run a task.
+pub struct DownloadJob {
+    // Task directory, it is removed once the task is over.
+    pub directory: PathBuf,
+    pub cancellation_token: CancellationToken,
+    pub progress: SharedProgress,
+    // Outputs are listed here one by one as soon as each of them is finished,
+    // so that they can be sent while the rest is still downloading.
+    pub finished_files_tx: Sender<FileInfo>,
+    pub proxy: SharedProxy,
+}
+
+pub trait Downloader {
+    // Whether this downloader can handle the URL it was created for.
+    async fn probe(&self) -> bool;
+    // Runs until everything is downloaded or the job is cancelled. Fails if the download was stopped
+    // or given up on, even if some files were finished before that.
+    // Returns diagnostics that explain why nothing was downloaded, if that's the case.
+    async fn download(&self, job: DownloadJob) -> Result<String, Box<dyn Error + Send + Sync>>;
+}
+
+// Static dispatch over the available downloaders.
+pub enum AnyDownloader {
+    Http(HttpDownloader),
+    Process(ProcessDownloader),
+}
+
+impl Downloader for AnyDownloader {
+    async fn probe(&self) -> bool {
+        match self {
+            AnyDownloader::Http(downloader) => downloader.probe().await,
+            AnyDownloader::Process(downloader) => downloader.probe().await,
+        }
+    }
+
+    async fn download(&self, job: DownloadJob) -> Result<String, Box<dyn Error + Send + Sync>> {
+        match self {
+            AnyDownloader::Http(downloader) => downloader.download(job).await,
+            AnyDownloader::Process(downloader) => downloader.download(job).await,
+        }
+    }
+}
+
+// Direct links to files are fetched natively, everything else goes to the program of the profile.
+#[tracing::instrument(skip_all)]
+pub async fn select(
+    profile: &'static MediaProfile,
+    url: Url,
+    time_range: Option<TimeRange>,
+    settings: UserSettings,
+    user_id: Option<UserId>,
+    proxy: Option<Url>,
+) -> AnyDownloader {
+    match HttpDownloader::new(profile, url.clone(), time_range, &settings, proxy.clone()) {
+        Ok(downloader) if downloader.probe().await => {
+            debug!("Direct link, downloading natively ...");
+            return AnyDownloader::Http(downloader);
+        }
+        Ok(_) => {}
+        Err(e) => warn!("HTTP client is not available: {e}"),
+    }
+    AnyDownloader::Process(ProcessDownloader::new(
+        profile, url, time_range, settings, user_id, proxy,
+    ))
+}
@@ -0,0 +1,442 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use teloxide::types::UserId;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::mpsc::Sender;
+use tokio_util::sync::CancellationToken;
+use url::Url;
+
+use super::error::{DownloadError, DownloadErrorKind};
+use super::{DownloadJob, Downloader, INACTIVITY_TIMEOUT_SECS};
+use crate::cookies;
+use crate::profiles::{DownloadTool, MediaProfile, SendMethod};
+use crate::proxies::{self, PROXIES};
+use crate::settings::{OversizePolicy, SubtitleMode, UserSettings};
+use crate::task::fileinfo::FileInfo;
+use crate::task::progress::{DownloadProgress, SharedProgress};
+use crate::task::timerange::TimeRange;
+
+// Both programs mention the proxy in their errors when it can't be reached, e.g. "Unable to connect to proxy".
+const PROXY_ERROR_MARKER: &str = "proxy";
+
+// Failures that can pass, such as rate limiting, are retried with exponential backoff.
+const RETRY_ATTEMPTS: u32 = 3;
+const RETRY_DELAY_SECS: u64 = 30;
+const ARCHIVE_FILE_NAME: &str = ".archive";
+
+// yt-dlp or gallery-dl, run as a child process.
+pub struct ProcessDownloader {
+    profile: &'static MediaProfile,
+    url: Url,
+    time_range: Option<TimeRange>,
+    settings: UserSettings,
+    // Private cookies of this user are used for the URL.
+    user_id: Option<UserId>,
+    proxy: Option<Url>,
+}
+
+impl ProcessDownloader {
+    pub fn new(
+        profile: &'static MediaProfile,
+        url: Url,
+        time_range: Option<TimeRange>,
+        settings: UserSettings,
+        user_id: Option<UserId>,
+        proxy: Option<Url>,
+    ) -> Self {
+        Self {
+            profile,
+            url,
+            time_range,
+            settings,
+            user_id,
+            proxy,
+        }
+    }
+}
+
+impl Downloader for ProcessDownloader {
+    // Both programs have generic extractors, unsupported URLs are reported while downloading.
+    async fn probe(&self) -> bool {
+        true
+    }
+
+    async fn download(&self, job: DownloadJob) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let cookie_jar = cookies::jar_for(&self.url, self.user_id);
+        let (program, mut args) = match self.profile.downloader {
+            DownloadTool::YtDlp => (
+                "yt-dlp",
+                generate_yt_dlp_args(
+                    self.profile,
+                    self.url.clone(),
+                    self.time_range,
+                    &self.settings,
+                    cookie_jar.as_deref(),
+                ),
+            ),
+            DownloadTool::GalleryDl => (
+                "gallery-dl",
+                generate_gallery_dl_args(
+                    self.profile,
+                    self.url.clone(),
+                    &job.directory,
+                    cookie_jar.as_deref(),
+                ),
+            ),
+        };
+        // Both programs take the same options. Retries skip the items that the archive lists as finished.
+        let archive = job.directory.join(ARCHIVE_FILE_NAME);
+        args.splice(
+            0..0,
+            [
+                String::from("--download-archive"),
+                archive.display().to_string(),
+            ],
+        );
+        let mut proxy = self.proxy.clone();
+        let mut attempt = 1;
+        loop {
+            let mut attempt_args = args.clone();
+            if let Some(proxy) = &proxy {
+                attempt_args.splice(0..0, [String::from("--proxy"), proxy.to_string()]);
+            }
+            let output = run_downloader(
+                program,
+                job.directory.clone(),
+                attempt_args,
+                job.cancellation_token.clone(),
+                job.progress.clone(),
+                job.finished_files_tx.clone(),
+            )
+            .await?;
+            let diagnostics = String::from_utf8_lossy(&output.stderr).into_owned();
+            if output.status.success() {
+                // Explains an empty result, e.g. when the only file was over the size limit.
+                return Ok(diagnostics);
+            }
+            if let Some(proxy) = &proxy
+                && diagnostics.to_lowercase().contains(PROXY_ERROR_MARKER)
+            {
+                proxies::report_failure(proxy);
+            }
+            let error = DownloadError::classify(&diagnostics);
+            if !error.is_retryable() || attempt == RETRY_ATTEMPTS {
+                return Err(error.into());
+            }
+            let delay = RETRY_DELAY_SECS * 2u64.pow(attempt - 1);
+            warn!(
+                "Attempt {attempt}/{RETRY_ATTEMPTS} failed ({:?}), retrying in {delay} seconds ...",
+                error.kind
+            );
+            tokio::select! {
+                _ = job.cancellation_token.cancelled() => {
+                    return Err(DownloadError::new(DownloadErrorKind::Cancelled).into());
+                }
+                _ = tokio::time::sleep(Duration::from_secs(delay)) => {}
+            }
+            // Picked again, so that a proxy that has just been reported as failed is skipped.
+            proxy = PROXIES.for_download(&self.url);
+            *job.proxy.lock().unwrap() = proxy.as_ref().map(proxies::without_credentials);
+            attempt += 1;
+        }
+    }
+}
+
+fn generate_yt_dlp_args(
+    profile: &MediaProfile,
+    url: Url,
+    time_range: Option<TimeRange>,
+    settings: &UserSettings,
+    cookie_jar: Option<&Path>,
+) -> Vec<String> {
+    // Common arguments every branch should have
+    let mut args = vec![
+        String::from("--concurrent-fragments"),
+        String::from("1"),
+        String::from("--skip-playlist-after-errors"),
+        String::from("5000"),
+        String::from("--windows-filenames"),
+        String::from("--no-write-info-json"),
+        String::from("--write-thumbnail"),
+        String::from("--convert-thumbnails"),
+        String::from("jpg"),
+        String::from("--sponsorblock-remove"),
+        String::from("sponsor"),
+        // Print every file once it is fully post-processed and moved, so it can be sent right away.
+        // --print implies --quiet, thus --progress is needed to keep progress lines.
+        String::from("--print"),
+        FileInfo::template(),
+        String::from("--progress"),
+        // Machine-readable progress, one line per update, parsed in run_downloader().
+        String::from("--newline"),
+        String::from("--progress-template"),
+        DownloadProgress::template(),
+    ];
+
+    // Metadata is stripped unless the user wants tagged audio with cover art.
+    if settings.tag_audio && profile.send == SendMethod::Audio {
+        args.extend(vec![
+            String::from("--embed-metadata"),
+            String::from("--embed-thumbnail"),
+        ]);
+    } else {
+        args.push(String::from("--no-embed-metadata"));
+    }
+
+    // Keyframes are forced at cuts so that the clip starts exactly where requested instead of the nearest keyframe.
+    if let Some(time_range) = time_range {
+        args.extend(vec![
+            String::from("--download-sections"),
+            time_range.to_download_section(),
+            String::from("--force-keyframes-at-cuts"),
+        ]);
+    }
+
+    if profile.send == SendMethod::Video && settings.subtitle_mode != SubtitleMode::Off {
+        args.extend(vec![
+            String::from("--write-subs"),
+            String::from("--sub-langs"),
+            settings.subtitle_languages.clone(),
+            String::from("--convert-subs"),
+            String::from("srt"),
+        ]);
+        if settings.auto_subtitles {
+            args.push(String::from("--write-auto-subs"));
+        }
+        if settings.subtitle_mode == SubtitleMode::Embed {
+            args.push(String::from("--embed-subs"));
+        }
+    }
+
+    let size_limit = settings.size_limit_bytes();
+    // Oversized files are useless if they are going to be skipped anyway.
+    if settings.oversize_policy == OversizePolicy::Skip {
+        args.extend(vec![String::from("--max-filesize"), size_limit.to_string()]);
+    }
+    // Media-specific arguments
+    args.extend(profile.args.iter().cloned());
+    // When fitting, prefer a format that fits, otherwise the best one is re-encoded after download.
+    // Merged formats report the sum of both parts as their size. The last --format given to yt-dlp wins.
+    if settings.oversize_policy == OversizePolicy::Fit && profile.send == SendMethod::Video {
+        args.extend(vec![
+            String::from("--format"),
+            format!(
+                "(bv*+ba/b)[filesize<{size_limit}]/(bv*+ba/b)[filesize_approx<{size_limit}]/bestvideo+bestaudio/best"
+            ),
+        ]);
+    }
+    args.push(String::from(url));
+
+    // Insert cookies arguments at the beginning if a cookie jar matches the URL
+    if let Some(cookie_jar) = cookie_jar {
+        args.insert(0, String::from("--cookies"));
+        args.insert(1, cookie_jar.display().to_string());
+    }
+
+    args
+}
+
+// Every file goes straight into the task directory, next to its metadata that holds the post text.
+fn generate_gallery_dl_args(
+    profile: &MediaProfile,
+    url: Url,
+    directory: &Path,
+    cookie_jar: Option<&Path>,
+) -> Vec<String> {
+    let mut args = vec![
+        String::from("--directory"),
+        directory.display().to_string(),
+        String::from("--write-metadata"),
+    ];
+    if let Some(cookie_jar) = cookie_jar {
+        args.extend(vec![
+            String::from("--cookies"),
+            cookie_jar.display().to_string(),
+        ]);
+    }
+    args.extend(profile.args.iter().cloned());
+    args.push(String::from(url));
+    args
+}
+
+// Runs yt-dlp or gallery-dl and hands over every finished file as soon as it is ready.
+#[tracing::instrument(skip_all)]
+async fn run_downloader(
+    program: &str,
+    path: PathBuf,
+    args: Vec<String>,
+    cancellation_token: CancellationToken,
+    progress: SharedProgress,
+    finished_files_tx: Sender<FileInfo>,
+) -> Result<std::process::Output, Box<dyn Error + Send + Sync>> {
+    debug!("Downloading with {program} ...");
+    let mut cmd = Command::new(program);
+    std::fs::create_dir_all(&path)?;
+    cmd.current_dir(&path)
+        .env("LC_ALL", "en_US.UTF-8")
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped());
+
+    // Add all arguments
+    for arg in args {
+        cmd.arg(arg);
+    }
+
+    // Spawn the child process
+    let mut child = cmd.spawn()?;
+
+    // Get handles to stdout and stderr
+    let stdout = child.stdout.take().expect("Failed to capture stdout");
+    let stderr = child.stderr.take().expect("Failed to capture stderr");
+
+    // Create readers for the streams
+    let mut stdout_reader = BufReader::new(stdout).lines();
+    let mut stderr_reader = BufReader::new(stderr).lines();
+
+    let current_span_1 = tracing::Span::current();
+    let path_for_reader = path.clone();
+    let current_span_2 = tracing::Span::current();
+    // None while a finished file waits for the sender, the process is paused then and is not stalled.
+    let last_activity = Arc::new(Mutex::new(Some(Instant::now())));
+    let last_activity_stdout = last_activity.clone();
+    let last_activity_stderr = last_activity.clone();
+
+    // Spawn tasks to process stdout and stderr
+    let stdout_task = tokio::spawn(async move {
+        while let Ok(Some(line)) = stdout_reader.next_line().await {
+            *last_activity_stdout.lock().unwrap() = Some(Instant::now());
+            // Progress lines arrive several times per second, they are not worth logging.
+            if let Some(update) = DownloadProgress::parse_line(&line) {
+                *progress.lock().unwrap() = update;
+                continue;
+            }
+            let file_info = FileInfo::parse_line(&line)
+                .or_else(|| FileInfo::parse_downloaded_path(&line, &path_for_reader));
+            if let Some(mut file_info) = file_info {
+                tracing::debug!(parent: current_span_1.clone(), "Finished: {}", file_info.filepath.display());
+                // Relative paths are resolved against the download directory, absolute ones are kept as is.
+                file_info.filepath = path_for_reader.join(&file_info.filepath);
+                // An error means the receiver is gone because sending failed, the process is being stopped anyway.
+                *last_activity_stdout.lock().unwrap() = None;
+                if finished_files_tx.send(file_info).await.is_err() {
+                    break;
+                }
+                *last_activity_stdout.lock().unwrap() = Some(Instant::now());
+                continue;
+            }
+            tracing::trace!(parent: current_span_1.clone(), "stdout: {}", line);
+        }
+    });
+
+    let stderr_task = tokio::spawn(async move {
+        let mut error_traceback = String::new();
+        while let Ok(Some(line)) = stderr_reader.next_line().await {
+            // A line while the stdout reader waits for the sender doesn't end the pause.
+            if let Some(last_activity) = last_activity_stderr.lock().unwrap().as_mut() {
+                *last_activity = Instant::now();
+            }
+            error_traceback += &line;
+            error_traceback.push('\n');
+            tracing::warn!(parent: current_span_2.clone(), "stderr: {}", line);
+        }
+        error_traceback
+    });
+
+    // Use select! to wait for either completion or cancellation
+    let result = tokio::select! {
+        biased;
+        // Handle cancellation
+        _ = cancellation_token.cancelled() => {
+            if let Some(pid) = child.id() {
+                // Spawn the `kill` command with the PID as argument
+                let output = std::process::Command::new("kill")
+                    .arg(pid.to_string())
+                    .output();
+
+                match output {
+                    Ok(o) if o.status.success() => {}
+                    Ok(o) => {
+                        warn!(
+                            "Failed to send SIGTERM to child process (pid {}): kill exited with {:?}",
+                            pid, o.status.code()
+                        );
+                    }
+                    Err(e) => {
+                        warn!("Failed to execute kill command: {}", e);
+                    }
+                }
+
+                // Wait a moment for graceful shutdown
+                tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+
+                // If process is still running, force kill with SIGKILL
+                if let Err(e) = child.kill().await {
+                    warn!("Failed to kill child process: {}", e);
+                }
+            } else {
+                // Fallback to regular kill if no PID
+                if let Err(e) = child.kill().await {
+                    warn!("Failed to kill child process: {}", e);
+                }
+            }
+
+            // Wait for the process to exit
+            let _ = child.wait_with_output().await;
+
+            // Cancel the stream processing tasks
+            stdout_task.abort();
+            stderr_task.abort();
+
+            Err(DownloadError::new(DownloadErrorKind::Cancelled).into())
+        }
+        _ = stalled(last_activity) => {
+            warn!("Process made no progress for {INACTIVITY_TIMEOUT_SECS} seconds");
+            if let Err(e) = child.kill().await {
+                warn!("Failed to kill stalled process: {}", e);
+            }
+            let _ = child.wait_with_output().await;
+            stdout_task.abort();
+            stderr_task.abort();
+            Err(DownloadError::new(DownloadErrorKind::Timeout).into())
+        }
+        // Wait for the process to complete normally
+        status = child.wait() => {
+            //
+            match status {
+                Ok(_) => {
+                    // Wait for stream processing to complete
+                    let (_, stderr) = tokio::join!(stdout_task, stderr_task);
+                    let stderr_bytes = stderr.unwrap().into_bytes();
+                    let mut output = child.wait_with_output().await?;
+                    output.stderr = stderr_bytes;
+                    Ok(output)
+                }
+                Err(e) => {
+                    // Cancel the stream processing tasks on error
+                    stdout_task.abort();
+                    stderr_task.abort();
+                    Err(Box::<dyn Error + Send + Sync>::from(e))
+                }
+            }
+        }
+    };
+
+    Ok(result?)
+}
+
+// Resolves once the process has shown no activity for too long while not waiting for the sender.
+async fn stalled(last_activity: Arc<Mutex<Option<Instant>>>) {
+    let timeout = Duration::from_secs(INACTIVITY_TIMEOUT_SECS);
+    loop {
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        if let Some(last_activity) = *last_activity.lock().unwrap()
+            && last_activity.elapsed() >= timeout
+        {
+            return;
+        }
+    }
+}
@@ -1,24 +1,31 @@
 use std::error::Error;
 use std::time::Duration;
 
-use reqwest::Client as ReqwestClient;
+use reqwest::{Client as ReqwestClient, Proxy};
 use surrealdb::{Surreal, engine::remote::ws::Client as DbClient};
 use teloxide::{
     prelude::*,
     types::BotCommandScope,
-    types::{InlineKeyboardButton, InlineKeyboardMarkup, Me},
+    types::{BotCommand, Document, InlineKeyboardButton, InlineKeyboardMarkup, Me, UserId},
     utils::command::BotCommands,
 };
 use tracing::{debug, error, info, warn};
 use url::Url;
 
 use crate::{
+    admins,
+    cookies::{self, CookieJar},
     database::{self, DbRecord},
+    i18n, janitor,
     misc::die,
+    profiles::{DownloadTool, MediaProfile, PROFILES, ProfileId},
+    proxies::{PROXIES, redact},
+    ratelimit::LimitedBot,
+    settings::{SETTINGS_CALLBACK_PREFIX, UserSettings},
     task::{
         cancellation::{CancellationRegistry, TASK_REGISTRY},
-        mediatype::MediaType,
         state::TaskState,
+        timerange::TimeRange,
         traits::{HasTaskId, Task},
     },
     trackedmessage::TrackedMessage,
@@ -41,6 +48,14 @@ enum Command {
     Clear,
     /// Stop all running tasks
     Stop,
+    /// Configure downloads
+    Settings,
+    /// Set subtitle languages, e.g. /subtitles en,ru
+    Subtitles(String),
+    /// Set a caption template, e.g. /caption {title} by {uploader}
+    Caption(String),
+    /// Delete your private cookies
+    DeleteCookies,
 }
 
 // Initializes and configures the Telegram bot instance
@@ -50,8 +65,16 @@ fn bot_init() -> Bot {
     let bot_token = std::env::var("TELOXIDE_TOKEN").unwrap_or_else(|e| die(e.to_string()));
 
     // Configure HTTP client with extended timeout for file operations
-    let client = ReqwestClient::builder()
-        .timeout(Duration::from_secs(360))
+    let mut client = ReqwestClient::builder().timeout(Duration::from_secs(360));
+    if let Some(proxy) = &PROXIES.telegram {
+        info!(
+            "Connecting to the Telegram Bot API through {} ...",
+            redact(proxy)
+        );
+        let proxy = Proxy::all(proxy.as_str()).unwrap_or_else(|error| die(error.to_string()));
+        client = client.proxy(proxy);
+    }
+    let client = client
         .build()
         .unwrap_or_else(|error| die(error.to_string()));
 
@@ -70,6 +93,7 @@ fn bot_init() -> Bot {
 #[tracing::instrument]
 pub async fn run() {
     let bot = bot_init();
+    let limited_bot = LimitedBot::new(bot.clone());
     let db = database::db_init().await;
     // On boot there can't be Running tasks. Finalize all Running tasks as Failed.
     if let Ok(task_states) = TaskState::from_db_all(db.clone()).await {
@@ -83,13 +107,25 @@ pub async fn run() {
     }
     // Initialize cancellation registry.
     CancellationRegistry::new();
+    // Remove download directories left behind by interrupted tasks.
+    tokio::spawn(janitor::run(db.clone()));
     // Configure visible bot commands (exclude /start from UI)
     let mut commands = Command::bot_commands().to_vec();
     commands.retain(|c| c.command != "/start");
-    bot.set_my_commands(commands)
+    // Descriptions in the default language are shown to everyone whose language has no catalog.
+    limited_bot
+        .set_my_commands(localized_commands(&commands, i18n::DEFAULT_LANGUAGE))
         .scope(BotCommandScope::Default)
         .await
         .unwrap_or_else(|_| die("Failed to set bot commands.".to_string()));
+    for language in i18n::LANGUAGES {
+        limited_bot
+            .set_my_commands(localized_commands(&commands, language))
+            .scope(BotCommandScope::Default)
+            .language_code(language)
+            .await
+            .unwrap_or_else(|_| die(format!("Failed to set bot commands for '{language}'.")));
+    }
     // let bot_clone = bot.clone();
     // let db_clone = db.clone();
     // tokio::task::spawn(async move {
@@ -98,39 +134,54 @@ pub async fn run() {
     //         .unwrap();
     // });
     // Start event dispatcher
-    dispatcher(bot, db).await;
+    dispatcher(bot, limited_bot, db).await;
+}
+
+// Same commands with descriptions from the catalog of the language
+fn localized_commands(commands: &[BotCommand], language: &str) -> Vec<BotCommand> {
+    commands
+        .iter()
+        .map(|command| {
+            let key = format!("command.{}", command.command.trim_start_matches('/'));
+            BotCommand::new(command.command.clone(), i18n::text(language, &key))
+        })
+        .collect()
 }
 
-// Configures update dispatcher with handlers
+// Configures update dispatcher with handlers. Updates are received with the plain bot,
+// handlers get the rate limited one.
 #[tracing::instrument(skip_all)]
-async fn dispatcher(bot: Bot, db: Surreal<DbClient>) {
+async fn dispatcher(bot: Bot, limited_bot: LimitedBot, db: Surreal<DbClient>) {
     let handler = dptree::entry()
         .branch(Update::filter_message().endpoint(message_handler))
         .branch(Update::filter_callback_query().endpoint(callback_handler));
 
     Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![db])
+        .dependencies(dptree::deps![db, limited_bot])
         .distribution_function(|_| None::<std::convert::Infallible>)
         .build()
         .dispatch()
         .await;
 }
 
-// Generates media type selection keyboard
+// Number of media profile buttons in a keyboard row
+const PROFILE_BUTTONS_PER_ROW: usize = 2;
+
+// Generates media profile selection keyboard, in the order the profiles are defined
 fn make_keyboard() -> InlineKeyboardMarkup {
-    InlineKeyboardMarkup::new(vec![
-        vec![
-            InlineKeyboardButton::callback("Audio", "Audio"),
-            InlineKeyboardButton::callback("Video", "Video"),
-        ],
-        vec![InlineKeyboardButton::callback("Voice", "Voice")],
-    ])
+    InlineKeyboardMarkup::new(PROFILES.chunks(PROFILE_BUTTONS_PER_ROW).map(|row| {
+        row.iter()
+            .map(|profile| {
+                InlineKeyboardButton::callback(profile.label.clone(), profile.id.to_string())
+            })
+            .collect::<Vec<_>>()
+    }))
 }
 
 // Handles callback queries from inline keyboards
 #[tracing::instrument(skip_all, fields(user_id = %callback_query.from.id))]
 async fn callback_handler(
-    bot: Bot,
+    bot: LimitedBot,
     callback_query: CallbackQuery,
     db: Surreal<DbClient>,
 ) -> HandlerResult {
@@ -140,6 +191,17 @@ async fn callback_handler(
     };
     let message = callback_query.regular_message().unwrap();
 
+    // Settings buttons are not tied to any task
+    if let Some(data) = callback_query.data.as_deref()
+        && data.starts_with(SETTINGS_CALLBACK_PREFIX)
+    {
+        return settings_callback_handler(bot, &callback_query, db).await;
+    }
+
+    let language = UserSettings::from_db(message.chat.id, db.clone())
+        .await?
+        .language_for(Some(&callback_query.from));
+
     // Retrieve task states for current chat
     let task_states_from_db = TaskState::from_db_by_chat_id(message.chat.id, db.clone()).await?;
 
@@ -155,15 +217,15 @@ async fn callback_handler(
         None => return Ok(()),
     };
 
-    // Map callback data to media type
-    let media_type = match MediaType::from_callback_data(data) {
-        Some(media_type) => {
-            info!("User @{} selected {}.", username, media_type);
-            media_type
+    // Map callback data to media profile
+    let profile = match MediaProfile::find(&ProfileId::new(data)) {
+        Some(profile) => {
+            info!("User @{} selected {}.", username, profile.id);
+            profile
         }
         None => {
             bot.answer_callback_query(callback_query.id)
-                .text("Invalid selection")
+                .text(i18n::text(language, "invalid_selection"))
                 .await?;
             return Ok(());
         }
@@ -172,11 +234,13 @@ async fn callback_handler(
     bot.answer_callback_query(callback_query.id.clone()).await?;
 
     let chat_id = message.chat.id;
-    let text = format!("Selected {media_type}. Please send the content URL.");
+    let text = i18n::text_with(language, "profile_selected", &[("profile", &profile.label)]);
 
     // Transition task state from New to WaitingForUrl
     let mut task_state = states_new[0].clone();
-    task_state.to_waiting_for_url(media_type, db.clone()).await;
+    task_state
+        .to_waiting_for_url(profile.id.clone(), db.clone())
+        .await;
 
     // Update message with next instructions
     if let Err(e) = bot.edit_message_text(chat_id, message.id, &text).await {
@@ -186,11 +250,43 @@ async fn callback_handler(
     Ok(())
 }
 
+// Handles presses on the settings keyboard
+#[tracing::instrument(skip_all, fields(user_id = %callback_query.from.id))]
+async fn settings_callback_handler(
+    bot: LimitedBot,
+    callback_query: &CallbackQuery,
+    db: Surreal<DbClient>,
+) -> HandlerResult {
+    let message = callback_query.regular_message().unwrap();
+    let data = callback_query.data.as_deref().unwrap_or_default();
+    let mut settings = UserSettings::from_db(message.chat.id, db.clone()).await?;
+    if !settings.apply_callback_data(data) {
+        let language = settings.language_for(Some(&callback_query.from));
+        bot.answer_callback_query(callback_query.id.clone())
+            .text(i18n::text(language, "invalid_selection"))
+            .await?;
+        return Ok(());
+    }
+    settings.intodb(db).await?;
+    info!("Settings changed: {:?}.", settings);
+    bot.answer_callback_query(callback_query.id.clone()).await?;
+    // The text is edited too, as the language may have changed
+    let language = settings.language_for(Some(&callback_query.from));
+    if let Err(e) = bot
+        .edit_message_text(message.chat.id, message.id, settings.describe(language))
+        .reply_markup(settings.make_keyboard(language))
+        .await
+    {
+        error!("Message edit failed: {}", e);
+    }
+    Ok(())
+}
+
 // Handles incoming messages and commands, the unwrap in tracing macro is safe because all messages
 // that we process are from the real users with real IDs.
 #[tracing::instrument(skip_all, fields(user_id = %msg_from_user.from.clone().unwrap().id))]
 async fn message_handler(
-    bot: Bot,
+    bot: LimitedBot,
     msg_from_user: Message,
     me: Me,
     db: Surreal<DbClient>,
@@ -200,6 +296,45 @@ async fn message_handler(
         None => "noname".to_string(),
     };
     let chat_id = msg_from_user.chat.id;
+    let language = UserSettings::from_db(chat_id, db.clone())
+        .await?
+        .language_for(msg_from_user.from.as_ref());
+
+    // Cookie files are uploaded as documents. Only private chats take them, so that nobody else sees the file.
+    if let Some(document) = msg_from_user
+        .document()
+        .filter(|_| msg_from_user.chat.is_private())
+    {
+        info!("User @{username} uploaded a document ...");
+        let task_state = TaskState::try_from(&msg_from_user)?;
+        task_state.intodb(db.clone()).await?;
+        let task_session = task_state.get_inner_task_simple().unwrap();
+        task_session
+            .remember_related_message(&msg_from_user, db.clone())
+            .await?;
+
+        // Cookies of admins are shared with everyone unless the caption is exactly "private",
+        // cookies of other users are always private.
+        let user_id = msg_from_user.from.clone().unwrap().id;
+        let caption = msg_from_user
+            .caption()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+        let owner = match caption.as_str() {
+            "" | "shared" if admins::is_admin(user_id) => Some(None),
+            "" | "private" | "shared" => Some(Some(user_id)),
+            _ => None,
+        };
+        let text = match owner {
+            Some(owner) => receive_cookies(document, owner, language, bot.clone()).await?,
+            None => i18n::text(language, "invalid_cookies_caption"),
+        };
+        task_session
+            .send_and_remember_msg(&text, bot.clone(), db.clone())
+            .await?;
+        return Ok(());
+    }
 
     // Process text commands
     if let Some(text) = msg_from_user.text() {
@@ -216,9 +351,9 @@ async fn message_handler(
 
                 // Present media type selection
                 let keyboard = make_keyboard();
-                let text = "Select content type:";
+                let text = i18n::text(language, "select_content_type");
                 task_session
-                    .send_and_remember_msg_with_keyboard(text, keyboard, bot.clone(), db.clone())
+                    .send_and_remember_msg_with_keyboard(&text, keyboard, bot.clone(), db.clone())
                     .await?;
                 return Ok(());
             }
@@ -243,6 +378,112 @@ async fn message_handler(
                 }
                 return Ok(());
             }
+            Ok(Command::Settings) => {
+                info!("User @{username} did /settings ...");
+                // Initialize new task session
+                let task_state = TaskState::try_from(&msg_from_user)?;
+                task_state.intodb(db.clone()).await?;
+                let task_session = task_state.get_inner_task_simple().unwrap();
+                task_session
+                    .remember_related_message(&msg_from_user, db.clone())
+                    .await?;
+
+                let settings = UserSettings::from_db(chat_id, db.clone()).await?;
+                let text = settings.describe(language);
+                task_session
+                    .send_and_remember_msg_with_keyboard(
+                        &text,
+                        settings.make_keyboard(language),
+                        bot.clone(),
+                        db.clone(),
+                    )
+                    .await?;
+                return Ok(());
+            }
+            Ok(Command::Subtitles(languages)) => {
+                info!("User @{username} did /subtitles ...");
+                // Initialize new task session
+                let task_state = TaskState::try_from(&msg_from_user)?;
+                task_state.intodb(db.clone()).await?;
+                let task_session = task_state.get_inner_task_simple().unwrap();
+                task_session
+                    .remember_related_message(&msg_from_user, db.clone())
+                    .await?;
+
+                let mut settings = UserSettings::from_db(chat_id, db.clone()).await?;
+                let text = match settings.set_subtitle_languages(&languages, language) {
+                    Ok(_) => {
+                        settings.intodb(db.clone()).await?;
+                        i18n::text_with(
+                            language,
+                            "subtitle_languages_set",
+                            &[("languages", &settings.subtitle_languages)],
+                        )
+                    }
+                    Err(e) => {
+                        i18n::text_with(language, "invalid_subtitle_languages", &[("error", &e)])
+                    }
+                };
+                task_session
+                    .send_and_remember_msg(&text, bot.clone(), db.clone())
+                    .await?;
+                return Ok(());
+            }
+            Ok(Command::Caption(template)) => {
+                info!("User @{username} did /caption ...");
+                // Initialize new task session
+                let task_state = TaskState::try_from(&msg_from_user)?;
+                task_state.intodb(db.clone()).await?;
+                let task_session = task_state.get_inner_task_simple().unwrap();
+                task_session
+                    .remember_related_message(&msg_from_user, db.clone())
+                    .await?;
+
+                let mut settings = UserSettings::from_db(chat_id, db.clone()).await?;
+                let text = match settings.set_caption_template(&template, language) {
+                    Ok(_) if settings.caption_template.is_empty() => {
+                        settings.intodb(db.clone()).await?;
+                        i18n::text(language, "caption_removed")
+                    }
+                    Ok(_) => {
+                        settings.intodb(db.clone()).await?;
+                        i18n::text_with(
+                            language,
+                            "caption_template_set",
+                            &[("template", &settings.caption_template)],
+                        )
+                    }
+                    Err(e) => {
+                        i18n::text_with(language, "invalid_caption_template", &[("error", &e)])
+                    }
+                };
+                task_session
+                    .send_and_remember_msg(&text, bot.clone(), db.clone())
+                    .await?;
+                return Ok(());
+            }
+            Ok(Command::DeleteCookies) => {
+                info!("User @{username} did /deletecookies ...");
+                // Initialize new task session
+                let task_state = TaskState::try_from(&msg_from_user)?;
+                task_state.intodb(db.clone()).await?;
+                let task_session = task_state.get_inner_task_simple().unwrap();
+                task_session
+                    .remember_related_message(&msg_from_user, db.clone())
+                    .await?;
+
+                let user_id = msg_from_user.from.clone().unwrap().id;
+                let text = match cookies::delete_private(user_id)? {
+                    0 => i18n::text(language, "no_private_cookies"),
+                    count => {
+                        i18n::text_with(language, "private_cookies_deleted", &[("count", &count)])
+                    }
+                };
+                task_session
+                    .send_and_remember_msg(&text, bot.clone(), db.clone())
+                    .await?;
+                return Ok(());
+            }
             Ok(Command::Clear) => {
                 info!("User @{username} did /clear ...");
                 // Initialize new task session
@@ -314,17 +555,29 @@ async fn message_handler(
                             .await?;
 
                         // Process URL input
-                        if let Some(raw_url) = msg_from_user.text() {
-                            match Url::parse(raw_url) {
-                                Ok(url) => {
+                        if let Some(text) = msg_from_user.text() {
+                            match parse_url_and_time_range(
+                                text,
+                                &task_download_non_running.profile,
+                                language,
+                            ) {
+                                Ok((url, time_range)) => {
                                     // Create cancellation token for task, in case it needs to be stopped
                                     let task_cancellation_token = CancellationToken::new();
                                     // Mark task as running
                                     task_state
-                                        .to_running(url, db.clone(), task_cancellation_token)
+                                        .to_running(
+                                            url,
+                                            time_range,
+                                            msg_from_user.from.as_ref().map(|user| user.id),
+                                            language,
+                                            db.clone(),
+                                            task_cancellation_token,
+                                        )
                                         .await;
+                                    // The task records the proxy its download ended up using.
                                     let task_download_running =
-                                        task_state.get_inner_task_download().unwrap();
+                                        task_state.get_inner_task_download_mut().unwrap();
                                     let request_processing_result = task_download_running
                                         .process_request(bot.clone(), db.clone())
                                         .await;
@@ -339,8 +592,7 @@ async fn message_handler(
                                         }
                                     }
                                 }
-                                Err(e) => {
-                                    let text = format!("Invalid URL: {e}.");
+                                Err(text) => {
                                     task_download_non_running
                                         .send_and_remember_msg(&text, bot.clone(), db.clone())
                                         .await?;
@@ -358,6 +610,73 @@ async fn message_handler(
     Ok(())
 }
 
+// Validates an uploaded cookies.txt and saves it as the jar for its domains, returns the reply for the user.
+// Jars with an owner are private to that user, the others are shared.
+#[tracing::instrument(skip_all)]
+async fn receive_cookies(
+    document: &Document,
+    owner: Option<UserId>,
+    language: &str,
+    bot: LimitedBot,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    if document.file.size > cookies::MAX_COOKIES_FILE_SIZE {
+        return Ok(i18n::text(language, "cookies_too_large"));
+    }
+    let file = bot.get_file(document.file.id.clone()).await?;
+    let mut content = Vec::new();
+    bot.download_file(&file.path, &mut content).await?;
+    let jar = match CookieJar::parse(&String::from_utf8_lossy(&content), language) {
+        Ok(jar) => jar,
+        Err(e) => {
+            return Ok(i18n::text_with(
+                language,
+                "invalid_cookies",
+                &[("error", &e)],
+            ));
+        }
+    };
+    let (path, scope) = match owner {
+        Some(user_id) => (jar.save_private(user_id)?, "cookies_private_scope"),
+        None => (jar.save_shared()?, "cookies_shared_scope"),
+    };
+    info!("Saved cookies to {} ...", path.display());
+    Ok(format!(
+        "{}\n{}",
+        jar.describe(language),
+        i18n::text(language, scope)
+    ))
+}
+
+// The URL can be followed by a time range to clip, e.g. "https://youtu.be/... 1:02:10-1:03:00".
+// Only yt-dlp can clip, gallery-dl downloads posts as a whole.
+fn parse_url_and_time_range(
+    text: &str,
+    profile: &ProfileId,
+    language: &str,
+) -> Result<(Url, Option<TimeRange>), String> {
+    let mut words = text.split_whitespace();
+    let raw_url = words.next().unwrap_or_default();
+    let url = Url::parse(raw_url)
+        .map_err(|e| i18n::text_with(language, "invalid_url", &[("error", &e)]))?;
+    let time_range = words
+        .next()
+        .map(|text| TimeRange::parse(text, language))
+        .transpose()
+        .map_err(|e| i18n::text_with(language, "invalid_time_range", &[("error", &e)]))?;
+    if time_range.is_some()
+        && MediaProfile::find(profile)
+            .is_some_and(|profile| profile.downloader == DownloadTool::GalleryDl)
+    {
+        let error = i18n::text(language, "time_range_not_supported");
+        return Err(i18n::text_with(
+            language,
+            "invalid_time_range",
+            &[("error", &error)],
+        ));
+    }
+    Ok((url, time_range))
+}
+
 // This is a dangerous function. It does correctly resume tasks but it can result in a dead loop
 // where some running task crashes a program, then crashes it again and again when entering this function on boot
 // enable at your own risk
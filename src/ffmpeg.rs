@@ -0,0 +1,423 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+
+use crate::misc::get_video_metadata;
+
+// Each attempt adds parts when bitrate is so uneven that some part still doesn't fit.
+const SPLIT_ATTEMPTS: u64 = 3;
+// Parts are aimed below the limit because cuts can only happen on keyframes.
+const SPLIT_SAFETY_MARGIN: f64 = 0.9;
+
+// Telegram requirements for video stickers.
+const STICKER_SIDE: u32 = 512;
+// Duration stays slightly under the 3 seconds limit, as the last frame may extend past the cut.
+const STICKER_MAX_DURATION: f64 = 2.9;
+const STICKER_MAX_SIZE: u64 = 256 * 1024;
+const STICKER_ATTEMPTS: u32 = 3;
+
+// Encoded size drifts from the target bitrate, thus the target is aimed below the limit and lowered on every miss.
+const FIT_ATTEMPTS: u32 = 3;
+const FIT_SAFETY_MARGIN: f64 = 0.92;
+// Bitrates in kbit/s.
+const FIT_MAX_AUDIO_BITRATE: u64 = 128;
+const FIT_MIN_AUDIO_BITRATE: u64 = 32;
+const FIT_MIN_VIDEO_BITRATE: u64 = 100;
+
+#[tracing::instrument(skip_all)]
+async fn ffmpeg(args: Vec<OsString>) -> Result<(), String> {
+    trace!("ffmpeg {:?}", args);
+    let output = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-y"])
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| format!("Command execution failed: {e}"))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "ffmpeg failed with exit code {}: {}",
+            output.status.code().unwrap_or(-1),
+            String::from_utf8_lossy(&output.stderr)
+        ))
+    }
+}
+
+// Cuts a media file into sequential parts of at most max_part_size bytes without re-encoding.
+// Cuts happen on keyframes, parts are returned in playback order and the original is removed on success.
+#[tracing::instrument(skip_all)]
+pub async fn split_into_parts(path: &Path, max_part_size: u64) -> Result<Vec<PathBuf>, String> {
+    let size = path.metadata().map_err(|e| e.to_string())?.len();
+    let duration = get_video_metadata(&path.to_path_buf()).duration;
+    if duration == 0 {
+        return Err("Unable to determine duration".to_string());
+    }
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+    // Percent signs in titles would be interpreted by the segment muxer as a part of the pattern.
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().replace('%', "%%"))
+        .unwrap_or_default();
+    let pattern = path.with_file_name(format!("{stem}.part%03d.{extension}"));
+
+    let mut parts_count =
+        (size as f64 / (max_part_size as f64 * SPLIT_SAFETY_MARGIN)).ceil() as u64;
+    for attempt in 1..=SPLIT_ATTEMPTS {
+        let segment_time = (duration as u64).div_ceil(parts_count).max(1);
+        debug!(
+            "Splitting into {parts_count} parts of {segment_time}s, attempt {attempt}/{SPLIT_ATTEMPTS} ..."
+        );
+        ffmpeg(vec![
+            "-i".into(),
+            path.into(),
+            // Capital V skips attached pictures such as embedded cover art.
+            "-map".into(),
+            "0:V?".into(),
+            "-map".into(),
+            "0:a?".into(),
+            "-c".into(),
+            "copy".into(),
+            "-f".into(),
+            "segment".into(),
+            "-segment_time".into(),
+            segment_time.to_string().into(),
+            "-reset_timestamps".into(),
+            "1".into(),
+            pattern.clone().into(),
+        ])
+        .await?;
+
+        let parts = list_parts(path, &stem.replace("%%", "%"), extension);
+        let fits = parts.iter().all(|part| {
+            part.metadata()
+                .map(|metadata| metadata.len() < max_part_size)
+                .unwrap_or(false)
+        });
+        if fits {
+            let _ = std::fs::remove_file(path);
+            return Ok(parts);
+        }
+        for part in parts {
+            let _ = std::fs::remove_file(part);
+        }
+        parts_count += parts_count.div_ceil(2);
+    }
+    Err(format!("Unable to fit parts under {max_part_size} bytes"))
+}
+
+// Converts any video into a silent H.264 MP4 that Telegram plays as a looping animation.
+// Returns the path of the new file, the source is kept.
+#[tracing::instrument(skip_all)]
+pub async fn to_animation(
+    path: &Path,
+    max_duration: u32,
+    max_size: u64,
+) -> Result<PathBuf, String> {
+    let animation_path = path.with_extension("animation.mp4");
+    ffmpeg(vec![
+        "-i".into(),
+        path.into(),
+        "-t".into(),
+        max_duration.to_string().into(),
+        "-map".into(),
+        "0:V:0".into(),
+        "-an".into(),
+        // Even dimensions are required by H.264 with yuv420p.
+        "-vf".into(),
+        "fps='min(30,source_fps)',scale='min(640,iw)':-2".into(),
+        "-c:v".into(),
+        "libx264".into(),
+        "-preset".into(),
+        "veryfast".into(),
+        "-crf".into(),
+        "26".into(),
+        "-pix_fmt".into(),
+        "yuv420p".into(),
+        "-movflags".into(),
+        "+faststart".into(),
+        animation_path.clone().into(),
+    ])
+    .await?;
+    let size = animation_path.metadata().map_err(|e| e.to_string())?.len();
+    if size >= max_size {
+        fit_to_size(&animation_path, max_size).await?;
+    }
+    Ok(animation_path)
+}
+
+// Converts any video into a square H.264/AAC MP4 of length x length pixels that Telegram shows as a round video note.
+// The picture is cropped around its center. Returns the path of the new file, the source is kept.
+#[tracing::instrument(skip_all)]
+pub async fn to_video_note(path: &Path, length: u32, max_duration: u32) -> Result<PathBuf, String> {
+    let video_note_path = path.with_extension("note.mp4");
+    ffmpeg(vec![
+        "-i".into(),
+        path.into(),
+        "-t".into(),
+        max_duration.to_string().into(),
+        "-map".into(),
+        "0:V:0".into(),
+        "-map".into(),
+        "0:a:0?".into(),
+        // Crop is centered by default.
+        "-vf".into(),
+        format!("crop='min(iw,ih)':'min(iw,ih)',scale={length}:{length},setsar=1").into(),
+        "-c:v".into(),
+        "libx264".into(),
+        "-preset".into(),
+        "veryfast".into(),
+        "-crf".into(),
+        "26".into(),
+        "-pix_fmt".into(),
+        "yuv420p".into(),
+        "-c:a".into(),
+        "aac".into(),
+        "-b:a".into(),
+        "96k".into(),
+        "-movflags".into(),
+        "+faststart".into(),
+        video_note_path.clone().into(),
+    ])
+    .await?;
+    Ok(video_note_path)
+}
+
+// Converts any video into a silent square VP9 WebM that Telegram accepts as a video sticker: 512x512 pixels,
+// under 3 seconds and under 256 KB. The picture is cropped around its center like a video note.
+// Returns the path of the new file, the source is kept.
+#[tracing::instrument(skip_all)]
+pub async fn to_sticker(path: &Path) -> Result<PathBuf, String> {
+    let sticker_path = path.with_extension("sticker.webm");
+    // Bitrate in kbit/s that would fill the limit exactly, lowered on every miss.
+    let mut bitrate = (STICKER_MAX_SIZE as f64 * 8.0 / STICKER_MAX_DURATION / 1000.0) as u64;
+    for attempt in 1..=STICKER_ATTEMPTS {
+        bitrate = (bitrate as f64 * FIT_SAFETY_MARGIN) as u64;
+        debug!("Encoding sticker at {bitrate}k, attempt {attempt}/{STICKER_ATTEMPTS} ...");
+        ffmpeg(vec![
+            "-i".into(),
+            path.into(),
+            "-t".into(),
+            STICKER_MAX_DURATION.to_string().into(),
+            "-map".into(),
+            "0:V:0".into(),
+            "-an".into(),
+            "-vf".into(),
+            format!(
+                "fps='min(30,source_fps)',crop='min(iw,ih)':'min(iw,ih)',scale={STICKER_SIDE}:{STICKER_SIDE},setsar=1"
+            )
+            .into(),
+            "-c:v".into(),
+            "libvpx-vp9".into(),
+            "-b:v".into(),
+            format!("{bitrate}k").into(),
+            "-maxrate".into(),
+            format!("{bitrate}k").into(),
+            "-bufsize".into(),
+            format!("{bitrate}k").into(),
+            "-pix_fmt".into(),
+            "yuva420p".into(),
+            sticker_path.clone().into(),
+        ])
+        .await?;
+        let size = sticker_path.metadata().map_err(|e| e.to_string())?.len();
+        if size < STICKER_MAX_SIZE {
+            return Ok(sticker_path);
+        }
+        bitrate = (bitrate as f64 * STICKER_MAX_SIZE as f64 / size as f64) as u64;
+    }
+    let _ = std::fs::remove_file(&sticker_path);
+    Err(format!(
+        "Unable to fit the sticker under {STICKER_MAX_SIZE} bytes"
+    ))
+}
+
+// Re-encodes a media file in place with a bitrate computed from its duration, so that it lands under max_size bytes.
+// Files with a video stream become H.264/AAC, audio-only files become MP3.
+#[tracing::instrument(skip_all)]
+pub async fn fit_to_size(path: &Path, max_size: u64) -> Result<(), String> {
+    let metadata = get_video_metadata(&path.to_path_buf());
+    if metadata.duration == 0 {
+        return Err("Unable to determine duration".to_string());
+    }
+    // Cover art of tagged audio has dimensions too, but it is not something to encode as a video.
+    let has_video = has_video_stream(path).await;
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+    let temp_path = path.with_extension(format!("fit.{extension}"));
+
+    let mut margin = FIT_SAFETY_MARGIN;
+    for attempt in 1..=FIT_ATTEMPTS {
+        // Total bitrate in kbit/s that fits the limit.
+        let total_bitrate =
+            (max_size as f64 * 8.0 * margin / metadata.duration as f64 / 1000.0) as u64;
+        let audio_bitrate = if has_video {
+            (total_bitrate / 4).clamp(FIT_MIN_AUDIO_BITRATE, FIT_MAX_AUDIO_BITRATE)
+        } else {
+            total_bitrate.min(320)
+        };
+        let video_bitrate = total_bitrate.saturating_sub(audio_bitrate);
+        if audio_bitrate < FIT_MIN_AUDIO_BITRATE
+            || (has_video && video_bitrate < FIT_MIN_VIDEO_BITRATE)
+        {
+            return Err(format!(
+                "Media is too long to fit into {} MB",
+                max_size / 1_000_000
+            ));
+        }
+        debug!(
+            "Re-encoding at {video_bitrate}k video and {audio_bitrate}k audio, attempt {attempt}/{FIT_ATTEMPTS} ..."
+        );
+
+        let mut args: Vec<OsString> = vec!["-i".into(), path.into()];
+        if has_video {
+            args.extend([
+                "-map".into(),
+                "0:V:0".into(),
+                "-map".into(),
+                "0:a:0?".into(),
+                "-c:v".into(),
+                "libx264".into(),
+                "-preset".into(),
+                "veryfast".into(),
+                "-b:v".into(),
+                format!("{video_bitrate}k").into(),
+                "-maxrate".into(),
+                format!("{video_bitrate}k").into(),
+                "-bufsize".into(),
+                format!("{}k", video_bitrate * 2).into(),
+                // Low bitrates look better at lower resolutions, even dimensions are required by H.264.
+                "-vf".into(),
+                format!(
+                    "scale=-2:'min({},ih)'",
+                    max_height_for_bitrate(video_bitrate)
+                )
+                .into(),
+                "-c:a".into(),
+                "aac".into(),
+                "-movflags".into(),
+                "+faststart".into(),
+            ]);
+        } else {
+            args.extend([
+                "-map".into(),
+                "0:a:0".into(),
+                "-c:a".into(),
+                "libmp3lame".into(),
+            ]);
+        }
+        args.extend([
+            "-b:a".into(),
+            format!("{audio_bitrate}k").into(),
+            temp_path.clone().into(),
+        ]);
+        ffmpeg(args).await?;
+
+        let encoded_size = temp_path.metadata().map_err(|e| e.to_string())?.len();
+        if encoded_size < max_size {
+            return std::fs::rename(&temp_path, path).map_err(|e| e.to_string());
+        }
+        margin *= max_size as f64 / encoded_size as f64 * FIT_SAFETY_MARGIN;
+    }
+    let _ = std::fs::remove_file(&temp_path);
+    Err(format!("Unable to fit under {max_size} bytes"))
+}
+
+// Whether the file has a video stream that is not an attached picture such as embedded cover art.
+async fn has_video_stream(path: &Path) -> bool {
+    match Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-select_streams",
+            "V",
+            "-show_entries",
+            "stream=index",
+            "-of",
+            "csv=p=0",
+        ])
+        .arg(path)
+        .output()
+        .await
+    {
+        Ok(output) => output.status.success() && !output.stdout.trim_ascii().is_empty(),
+        Err(e) => {
+            warn!("Failed to probe video streams: {e}");
+            false
+        }
+    }
+}
+
+// Renders subtitles into the picture of a video in place.
+#[tracing::instrument(skip_all)]
+pub async fn burn_subtitles(path: &Path, subtitles: &Path) -> Result<(), String> {
+    // The subtitles filter takes a path inside the filter graph where quotes, colons and commas need escaping.
+    // A copy under a plain name in the task directory, which is named after a UUID, needs none.
+    let plain_subtitles = path.with_file_name(".burn.srt");
+    std::fs::copy(subtitles, &plain_subtitles).map_err(|e| e.to_string())?;
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+    let temp_path = path.with_extension(format!("burn.{extension}"));
+    let result = ffmpeg(vec![
+        "-i".into(),
+        path.into(),
+        "-vf".into(),
+        format!("subtitles={}", plain_subtitles.display()).into(),
+        "-c:v".into(),
+        "libx264".into(),
+        "-preset".into(),
+        "veryfast".into(),
+        "-crf".into(),
+        "23".into(),
+        "-c:a".into(),
+        "copy".into(),
+        "-movflags".into(),
+        "+faststart".into(),
+        temp_path.clone().into(),
+    ])
+    .await;
+    let _ = std::fs::remove_file(&plain_subtitles);
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    std::fs::rename(&temp_path, path).map_err(|e| e.to_string())
+}
+
+fn max_height_for_bitrate(video_bitrate: u64) -> u32 {
+    match video_bitrate {
+        0..500 => 360,
+        500..1000 => 480,
+        1000..2500 => 720,
+        _ => 1080,
+    }
+}
+
+fn list_parts(path: &Path, stem: &str, extension: &str) -> Vec<PathBuf> {
+    let prefix = format!("{stem}.part");
+    let suffix = format!(".{extension}");
+    let mut parts: Vec<PathBuf> = path
+        .parent()
+        .and_then(|parent| std::fs::read_dir(parent).ok())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|part| {
+            part.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(&prefix) && name.ends_with(&suffix))
+        })
+        .collect();
+    // Zero-padded numbering makes alphabetic order equal to playback order.
+    parts.sort();
+    parts
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use crate::misc::die;
+
+// Languages that have a catalog. The first one is the default and the fallback for missing messages.
+pub const LANGUAGES: [&str; 2] = ["en", "ru"];
+pub const DEFAULT_LANGUAGE: &str = LANGUAGES[0];
+
+// Catalogs are compiled into the binary, so that a deployment can't end up without messages.
+const CATALOG_FILES: [(&str, &str); 2] = [
+    ("en", include_str!("../locales/en.json")),
+    ("ru", include_str!("../locales/ru.json")),
+];
+
+lazy_static::lazy_static! {
+    pub static ref CATALOGS: HashMap<&'static str, HashMap<String, String>> = load_catalogs();
+}
+
+fn load_catalogs() -> HashMap<&'static str, HashMap<String, String>> {
+    let catalogs: HashMap<&'static str, HashMap<String, String>> = CATALOG_FILES
+        .iter()
+        .map(|(language, json)| {
+            let catalog = serde_json::from_str(json).unwrap_or_else(|e| {
+                die(format!("Catalog of language '{language}' is invalid: {e}"))
+            });
+            (*language, catalog)
+        })
+        .collect();
+    // Missing messages fall back to the default language, which is worth knowing about but not fatal.
+    for (language, catalog) in &catalogs {
+        for key in catalogs[DEFAULT_LANGUAGE].keys() {
+            if !catalog.contains_key(key) {
+                warn!("Catalog of language '{language}' has no message '{key}'.");
+            }
+        }
+    }
+    catalogs
+}
+
+// The language chosen in the settings first, then the language of the user's Telegram app, e.g. "ru" for "ru-RU".
+pub fn pick(chosen: Option<&str>, language_code: Option<&str>) -> &'static str {
+    [chosen, language_code]
+        .into_iter()
+        .flatten()
+        .find_map(|code| {
+            let code = code.split(['-', '_']).next()?.to_lowercase();
+            LANGUAGES.into_iter().find(|language| *language == code)
+        })
+        .unwrap_or(DEFAULT_LANGUAGE)
+}
+
+// Message from the catalog of the language. Falls back to the default language, then to the key itself.
+pub fn text(language: &str, key: &str) -> String {
+    [language, DEFAULT_LANGUAGE]
+        .into_iter()
+        .find_map(|language| CATALOGS.get(language)?.get(key))
+        .cloned()
+        .unwrap_or_else(|| {
+            warn!("Message '{key}' is not in any catalog.");
+            key.to_string()
+        })
+}
+
+// Same as text() with {name} placeholders filled in. Values are inserted as is, even if they contain braces.
+pub fn text_with(language: &str, key: &str, values: &[(&str, &dyn Display)]) -> String {
+    let message = text(language, key);
+    let mut filled = String::new();
+    let mut rest = message.as_str();
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}').map(|length| start + length) else {
+            break;
+        };
+        filled.push_str(&rest[..start]);
+        match values
+            .iter()
+            .find(|(name, _)| *name == &rest[start + 1..end])
+        {
+            Some((_, value)) => filled.push_str(&value.to_string()),
+            None => filled.push_str(&rest[start..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    filled.push_str(rest);
+    filled
+}
@@ -0,0 +1,99 @@
+use std::error::Error;
+use std::path::Path;
+use std::time::Duration;
+
+use surrealdb::{Surreal, engine::remote::ws::Client as DbClient};
+use uuid::Uuid;
+
+use crate::{
+    FILE_STORAGE,
+    misc::{FolderData, cleanup, sleep},
+    task::{id::TaskId, state::TaskState},
+};
+
+// How often the download storage is swept for orphaned directories.
+const SWEEP_INTERVAL_SECS: u32 = 60 * 60;
+// Directories younger than this are left alone, so that a task that has just been
+// registered in the DB but whose state is not yet visible is never touched.
+const GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+// Periodically removes FILE_STORAGE/<task_id> directories that outlived their task.
+// This happens when the process dies mid-task, as cleanup otherwise happens only inside the task itself.
+#[tracing::instrument(skip_all)]
+pub async fn run(db: Surreal<DbClient>) {
+    debug!("Starting janitor task ...");
+    loop {
+        if let Err(e) = sweep(db.clone()).await {
+            warn!("Janitor sweep failed: {e}");
+        }
+        sleep(SWEEP_INTERVAL_SECS).await;
+    }
+}
+
+#[tracing::instrument(skip_all)]
+async fn sweep(db: Surreal<DbClient>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    trace!("Sweeping {FILE_STORAGE} ...");
+    let entries = match std::fs::read_dir(FILE_STORAGE) {
+        Ok(entries) => entries,
+        // Nothing was ever downloaded, nothing to clean up.
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut reclaimed_bytes: usize = 0;
+    let mut removed_count: usize = 0;
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let age = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .unwrap_or_default();
+        if age < GRACE_PERIOD {
+            continue;
+        }
+        if is_owned_by_running_task(&path, db.clone()).await? {
+            continue;
+        }
+        let path_str = path.to_string_lossy().to_string();
+        let folder_data = FolderData::from(&path_str);
+        debug!(
+            "Removing orphaned directory {path_str} ({}) ...",
+            folder_data.format_bytes_to_megabytes()
+        );
+        reclaimed_bytes += folder_data.size_in_bytes;
+        removed_count += 1;
+        cleanup(path);
+    }
+
+    if removed_count > 0 {
+        info!(
+            "Janitor removed {removed_count} orphaned directories, reclaimed {:.2} MB.",
+            reclaimed_bytes as f64 / (1024.0 * 1024.0)
+        );
+    }
+    Ok(())
+}
+
+// Directories are named after the task UUID. Anything that doesn't parse as one is unknown and thus orphaned.
+async fn is_owned_by_running_task(
+    path: &Path,
+    db: Surreal<DbClient>,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let uuid = match path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| Uuid::parse_str(name).ok())
+    {
+        Some(uuid) => uuid,
+        None => return Ok(false),
+    };
+    let task_states = TaskState::from_db_by_task_id(TaskId { uuid }, db).await?;
+    Ok(task_states
+        .iter()
+        .any(|state| matches!(state, TaskState::Running(_))))
+}
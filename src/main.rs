@@ -2,9 +2,21 @@
 extern crate log;
 pub const CRATE_NAME: &str = module_path!();
 pub const FILE_STORAGE: &str = "/tmp/telepirate-downloads";
+// Local Telegram API allows bots sending only files under 2 GB.
+pub const MAX_UPLOAD_SIZE: u64 = 2_000_000_000;
+mod admins;
+mod cookies;
 mod database;
+mod downloader;
 mod engine;
+mod ffmpeg;
+mod i18n;
+mod janitor;
 mod misc;
+mod profiles;
+mod proxies;
+mod ratelimit;
+mod settings;
 mod task;
 mod tracing;
 mod trackedmessage;
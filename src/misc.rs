@@ -5,7 +5,6 @@ use std::time::Duration;
 
 use std::ffi::OsStr;
 use std::process::Command;
-use walkdir::DirEntry;
 use walkdir::WalkDir;
 
 #[tracing::instrument(skip_all)]
@@ -23,9 +22,13 @@ pub fn boot() {
     use crate::tracing;
     tracing::init();
     check_dependency("yt-dlp");
+    check_dependency("gallery-dl");
     check_dependency("ffmpeg");
     check_dependency("magick");
     check_dependency("jpegoptim");
+    lazy_static::initialize(&crate::profiles::PROFILES);
+    lazy_static::initialize(&crate::proxies::PROXIES);
+    lazy_static::initialize(&crate::i18n::CATALOGS);
     let _ = ctrlc::set_handler(move || {
         info!("Stopping ...");
         update();
@@ -57,24 +60,10 @@ pub fn die(reason: impl Into<String>) -> ! {
 
 pub struct FolderData {
     pub size_in_bytes: usize,
-    pub file_count: usize,
 }
 
 impl FolderData {
     pub fn from(path_to_directory: &str) -> Self {
-        // Collect files for counting (thumbnails for mp4, actual files for others)
-        let files_to_count_amount: Vec<DirEntry> = WalkDir::new(path_to_directory)
-            .into_iter()
-            .filter_map(|entry| entry.ok())
-            .filter(|entry| entry.path().is_file())
-            .filter(|entry| entry.path().extension() != Some(OsStr::new("part")))
-            // For counting all kinds of downloads, use jpg thumbnails, as yt-dlp intermediate objects are hard to track
-            .filter(|entry| entry.path().extension() == Some(OsStr::new("jpg")))
-            .collect();
-
-        let file_count = files_to_count_amount.len();
-
-        // Calculate total size: use mp4 files for size if media type is mp4, otherwise use counted files
         let mut size_in_bytes: usize = 0;
 
         for entry in WalkDir::new(path_to_directory)
@@ -89,10 +78,7 @@ impl FolderData {
             }
         }
 
-        FolderData {
-            size_in_bytes,
-            file_count,
-        }
+        FolderData { size_in_bytes }
     }
 
     pub fn format_bytes_to_megabytes(&self) -> String {
@@ -0,0 +1,185 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::misc::die;
+use crate::settings::SETTINGS_CALLBACK_PREFIX;
+
+// Operators can replace the built-in profiles with their own file, see config/profiles.json for the format.
+const PROFILES_PATH_VARIABLE: &str = "TELEPIRATE_PROFILES";
+const DEFAULT_PROFILES_PATH: &str = "/app/config/profiles.json";
+const BUILTIN_PROFILES: &str = include_str!("../config/profiles.json");
+// Telegram limits callback data to 64 bytes, and profile IDs are used as callback data.
+const MAX_PROFILE_ID_LENGTH: usize = 64;
+
+// Profiles are loaded once at boot, a broken file stops the bot instead of failing every download.
+lazy_static::lazy_static! {
+    pub static ref PROFILES: Vec<MediaProfile> = load();
+}
+
+// Program that downloads the URL.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DownloadTool {
+    #[default]
+    YtDlp,
+    // For image posts and galleries, yt-dlp downloads only videos.
+    GalleryDl,
+}
+
+// Conversion applied by ffmpeg to every finished file.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PostProcess {
+    #[default]
+    None,
+    Animation,
+    VideoNote,
+    Sticker,
+}
+
+// How finished files are delivered to the chat.
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SendMethod {
+    Audio,
+    Video,
+    Voice,
+    Document,
+    Animation,
+    VideoNote,
+    Sticker,
+    // Up to 10 images and videos per message.
+    Album,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MediaProfile {
+    pub id: ProfileId,
+    // Button text.
+    pub label: String,
+    #[serde(default)]
+    pub downloader: DownloadTool,
+    // Passed to the downloader before the URL.
+    #[serde(default)]
+    pub args: Vec<String>,
+    // Extension of the files to send, other files such as thumbnails are ignored. Any file is sent if not set.
+    #[serde(default)]
+    pub extension: Option<String>,
+    #[serde(default)]
+    pub postprocess: PostProcess,
+    pub send: SendMethod,
+}
+
+impl MediaProfile {
+    pub fn find(id: &ProfileId) -> Option<&'static Self> {
+        PROFILES.iter().find(|profile| profile.id == *id)
+    }
+
+    pub fn matches_extension(&self, path: &Path) -> bool {
+        match &self.extension {
+            Some(extension) => path.extension() == Some(OsStr::new(extension)),
+            None => true,
+        }
+    }
+}
+
+// Profile IDs are stored in tasks instead of the profiles themselves, so that edited profiles apply to pending tasks.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(into = "String", from = "StoredProfileId")]
+pub struct ProfileId(String);
+
+impl ProfileId {
+    pub fn new(id: &str) -> Self {
+        Self(id.to_string())
+    }
+}
+
+impl From<ProfileId> for String {
+    fn from(id: ProfileId) -> Self {
+        id.0
+    }
+}
+
+impl std::fmt::Display for ProfileId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Records written before profiles existed store a media type instead, e.g. {"filetype": "Mp3"}.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredProfileId {
+    Id(String),
+    MediaType { filetype: String },
+}
+
+impl From<StoredProfileId> for ProfileId {
+    fn from(stored: StoredProfileId) -> Self {
+        match stored {
+            StoredProfileId::Id(id) => Self(id),
+            StoredProfileId::MediaType { filetype } => Self::new(match filetype.as_str() {
+                "Mp3" => "audio",
+                "Mp4" => "video",
+                "Voice" => "voice",
+                "File" => "file",
+                "Animation" => "animation",
+                "VideoNote" => "video_note",
+                "Photo" => "photos",
+                "Sticker" => "sticker",
+                other => other,
+            }),
+        }
+    }
+}
+
+#[tracing::instrument]
+fn load() -> Vec<MediaProfile> {
+    let path = std::env::var(PROFILES_PATH_VARIABLE).unwrap_or(DEFAULT_PROFILES_PATH.to_string());
+    let profiles = match std::fs::read_to_string(&path) {
+        Ok(json) => {
+            info!("Loading media profiles from {path} ...");
+            parse(&json).unwrap_or_else(|e| die(format!("Invalid media profiles in {path}: {e}")))
+        }
+        Err(_) => {
+            debug!("No media profiles at {path}, using the built-in ones ...");
+            parse(BUILTIN_PROFILES)
+                .unwrap_or_else(|e| die(format!("Invalid built-in media profiles: {e}")))
+        }
+    };
+    let ids: Vec<String> = profiles
+        .iter()
+        .map(|profile| profile.id.to_string())
+        .collect();
+    debug!("Media profiles: {}.", ids.join(", "));
+    profiles
+}
+
+fn parse(json: &str) -> Result<Vec<MediaProfile>, String> {
+    let profiles: Vec<MediaProfile> = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    if profiles.is_empty() {
+        return Err("at least one profile is required".to_string());
+    }
+    let mut ids = HashSet::new();
+    for profile in &profiles {
+        let id = &profile.id.0;
+        if id.is_empty() || id.len() > MAX_PROFILE_ID_LENGTH {
+            return Err(format!(
+                "profile ID '{id}' must be 1 to {MAX_PROFILE_ID_LENGTH} bytes long"
+            ));
+        }
+        // Settings buttons are told apart from profile buttons by this prefix.
+        if id.starts_with(SETTINGS_CALLBACK_PREFIX) {
+            return Err(format!(
+                "profile ID '{id}' must not start with '{SETTINGS_CALLBACK_PREFIX}'"
+            ));
+        }
+        if !ids.insert(id) {
+            return Err(format!("profile ID '{id}' is used more than once"));
+        }
+    }
+    Ok(profiles)
+}
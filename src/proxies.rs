@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use url::Url;
+
+use crate::misc::die;
+
+// Proxies are optional, see config/proxies.example.json for the format.
+const PROXIES_PATH_VARIABLE: &str = "TELEPIRATE_PROXIES";
+const DEFAULT_PROXIES_PATH: &str = "/app/config/proxies.json";
+// Schemes that both yt-dlp and reqwest understand.
+const SUPPORTED_SCHEMES: [&str; 6] = ["http", "https", "socks4", "socks4a", "socks5", "socks5h"];
+// A proxy that failed is skipped for this long, unless every proxy of its pool failed.
+const FAILURE_COOLDOWN_SECS: u64 = 600;
+
+lazy_static::lazy_static! {
+    pub static ref PROXIES: ProxyConfig = load();
+    // When each proxy failed last.
+    static ref FAILURES: Mutex<HashMap<Url, Instant>> = Mutex::new(HashMap::new());
+}
+
+// How a pool picks a proxy for a download.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Selection {
+    // Spreads downloads over all proxies of the pool.
+    #[default]
+    RoundRobin,
+    // Always the first proxy that works, the others are backups.
+    Failover,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProxyPool {
+    // Hosts the pool is for, subdomains included. The pool without domains is used for all other hosts.
+    #[serde(default)]
+    domains: Vec<String>,
+    // No proxies means direct connections for the domains of the pool.
+    #[serde(default)]
+    proxies: Vec<Url>,
+    #[serde(default)]
+    selection: Selection,
+    // Position of the next proxy for round-robin selection.
+    #[serde(skip)]
+    next: AtomicUsize,
+}
+
+#[derive(Default, Debug, Deserialize)]
+pub struct ProxyConfig {
+    // Used for the requests to the Telegram Bot API.
+    #[serde(default)]
+    pub telegram: Option<Url>,
+    // Used for downloads. The first pool that lists the host of the URL is used.
+    #[serde(default)]
+    pools: Vec<ProxyPool>,
+}
+
+impl ProxyConfig {
+    // Proxy for all requests of a download, None for a direct connection.
+    pub fn for_download(&self, url: &Url) -> Option<Url> {
+        let host = url.host_str()?.to_ascii_lowercase();
+        let pool = self
+            .pools
+            .iter()
+            .find(|pool| {
+                pool.domains
+                    .iter()
+                    .any(|domain| host == *domain || host.ends_with(&format!(".{domain}")))
+            })
+            .or_else(|| self.pools.iter().find(|pool| pool.domains.is_empty()))?;
+        let proxy = pool.pick()?;
+        debug!("Using proxy {} for {host} ...", redact(&proxy));
+        Some(proxy)
+    }
+
+    // Tasks store proxies without credentials, they are taken from the configuration when the proxy is used.
+    pub fn with_credentials(&self, proxy: &Url) -> Url {
+        let redacted = without_credentials(proxy);
+        self.pools
+            .iter()
+            .flat_map(|pool| pool.proxies.iter())
+            .find(|configured| without_credentials(configured) == redacted)
+            .unwrap_or(proxy)
+            .clone()
+    }
+}
+
+impl ProxyPool {
+    fn pick(&self) -> Option<Url> {
+        if self.proxies.is_empty() {
+            return None;
+        }
+        let working: Vec<&Url> = self
+            .proxies
+            .iter()
+            .filter(|proxy| !has_failed_recently(proxy))
+            .collect();
+        // When all of them failed, trying them again beats not downloading at all.
+        let candidates = if working.is_empty() {
+            self.proxies.iter().collect()
+        } else {
+            working
+        };
+        let proxy = match self.selection {
+            Selection::RoundRobin => {
+                candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()]
+            }
+            Selection::Failover => candidates[0],
+        };
+        Some(proxy.clone())
+    }
+}
+
+// The proxy is skipped by the following downloads until the cooldown is over.
+pub fn report_failure(proxy: &Url) {
+    warn!(
+        "Proxy {} failed, skipping it for {FAILURE_COOLDOWN_SECS} seconds.",
+        redact(proxy)
+    );
+    FAILURES
+        .lock()
+        .unwrap()
+        .insert(proxy.clone(), Instant::now());
+}
+
+fn has_failed_recently(proxy: &Url) -> bool {
+    FAILURES
+        .lock()
+        .unwrap()
+        .get(proxy)
+        .is_some_and(|failed_at| failed_at.elapsed() < Duration::from_secs(FAILURE_COOLDOWN_SECS))
+}
+
+// Proxy URL without credentials, safe to log and to store.
+pub fn redact(proxy: &Url) -> String {
+    without_credentials(proxy).to_string()
+}
+
+pub fn without_credentials(proxy: &Url) -> Url {
+    let mut proxy = proxy.clone();
+    let _ = proxy.set_username("");
+    let _ = proxy.set_password(None);
+    proxy
+}
+
+#[tracing::instrument]
+fn load() -> ProxyConfig {
+    let path = std::env::var(PROXIES_PATH_VARIABLE).unwrap_or(DEFAULT_PROXIES_PATH.to_string());
+    let Ok(json) = std::fs::read_to_string(&path) else {
+        debug!("No proxies at {path}, connecting directly ...");
+        return ProxyConfig::default();
+    };
+    info!("Loading proxies from {path} ...");
+    parse(&json).unwrap_or_else(|e| die(format!("Invalid proxies in {path}: {e}")))
+}
+
+fn parse(json: &str) -> Result<ProxyConfig, String> {
+    let mut config: ProxyConfig = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    let proxies = config
+        .pools
+        .iter()
+        .flat_map(|pool| pool.proxies.iter())
+        .chain(config.telegram.iter());
+    for proxy in proxies {
+        if !SUPPORTED_SCHEMES.contains(&proxy.scheme()) {
+            return Err(format!(
+                "proxy {} must use one of {}",
+                redact(proxy),
+                SUPPORTED_SCHEMES.join(", ")
+            ));
+        }
+    }
+    for pool in &mut config.pools {
+        for domain in &mut pool.domains {
+            *domain = domain.trim_start_matches('.').to_ascii_lowercase();
+        }
+    }
+    Ok(config)
+}
@@ -0,0 +1,404 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::IntoFuture;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use teloxide::net::Download;
+use teloxide::prelude::*;
+use teloxide::requests::{HasPayload, Output};
+use teloxide::types::{
+    BotCommand, CallbackQueryId, ChatAction, FileId, InputFile, InputMedia, MessageId,
+};
+use tokio::io::AsyncWrite;
+
+// Telegram's limits for bots: about 30 messages per second overall,
+// one per second in a private chat and 20 per minute in a group.
+const GLOBAL_LIMIT: usize = 30;
+const GLOBAL_PERIOD: Duration = Duration::from_secs(1);
+const PRIVATE_CHAT_LIMIT: usize = 1;
+const PRIVATE_CHAT_PERIOD: Duration = Duration::from_secs(1);
+const GROUP_LIMIT: usize = 20;
+const GROUP_PERIOD: Duration = Duration::from_secs(60);
+// Low priority calls leave this much of a budget to the calls that must not be dropped. Half of a group's budget
+// is kept, since chat actions and progress updates alone would use all of it. A private chat's single call per
+// second is not reserved, the updates come seldom enough to leave room in it.
+const GLOBAL_HEADROOM: usize = 5;
+const PRIVATE_CHAT_HEADROOM: usize = 0;
+const GROUP_HEADROOM: usize = 10;
+// Windows of chats that went quiet are forgotten once there are this many.
+const MAX_TRACKED_CHATS: usize = 1024;
+
+lazy_static::lazy_static! {
+    static ref BUDGETS: Mutex<Budgets> = Mutex::new(Budgets {
+        global: Window::new(GLOBAL_LIMIT, GLOBAL_PERIOD, GLOBAL_HEADROOM),
+        chats: HashMap::new(),
+    });
+}
+
+// Calls made within the last period.
+struct Window {
+    limit: usize,
+    period: Duration,
+    // Calls that low priority calls can't take.
+    headroom: usize,
+    calls: VecDeque<Instant>,
+}
+
+impl Window {
+    fn new(limit: usize, period: Duration, headroom: usize) -> Self {
+        Self {
+            limit,
+            period,
+            headroom,
+            calls: VecDeque::with_capacity(limit),
+        }
+    }
+
+    fn for_chat(chat_id: ChatId) -> Self {
+        if chat_id.is_user() {
+            Self::new(
+                PRIVATE_CHAT_LIMIT,
+                PRIVATE_CHAT_PERIOD,
+                PRIVATE_CHAT_HEADROOM,
+            )
+        } else {
+            Self::new(GROUP_LIMIT, GROUP_PERIOD, GROUP_HEADROOM)
+        }
+    }
+
+    fn forget_expired(&mut self, now: Instant) {
+        while self
+            .calls
+            .front()
+            .is_some_and(|call| now.duration_since(*call) >= self.period)
+        {
+            self.calls.pop_front();
+        }
+    }
+
+    fn free(&self) -> usize {
+        self.limit.saturating_sub(self.calls.len())
+    }
+
+    // A call can't take more than the whole window, otherwise it would never fit.
+    fn cost_of(&self, cost: usize) -> usize {
+        cost.min(self.limit)
+    }
+
+    // Free calls a call needs to go through, low priority ones leave the headroom free.
+    fn needed(&self, cost: usize, low_priority: bool) -> usize {
+        if low_priority {
+            self.cost_of(cost) + self.headroom
+        } else {
+            self.cost_of(cost)
+        }
+    }
+
+    // How long until this many calls are free.
+    fn wait_time(&self, now: Instant, needed: usize) -> Duration {
+        let missing = needed.saturating_sub(self.free());
+        match missing
+            .checked_sub(1)
+            .and_then(|index| self.calls.get(index))
+        {
+            Some(call) => (*call + self.period).saturating_duration_since(now),
+            None => Duration::ZERO,
+        }
+    }
+
+    fn record(&mut self, now: Instant, cost: usize) {
+        for _ in 0..self.cost_of(cost) {
+            self.calls.push_back(now);
+        }
+    }
+}
+
+struct Budgets {
+    global: Window,
+    chats: HashMap<ChatId, Window>,
+}
+
+impl Budgets {
+    // Takes calls from both budgets if there is room for them, otherwise tells how long to wait.
+    // Calls that don't go to a chat, such as file downloads, count against the global budget only.
+    fn take(
+        &mut self,
+        chat_id: Option<ChatId>,
+        cost: usize,
+        low_priority: bool,
+    ) -> Result<(), Duration> {
+        let now = Instant::now();
+        if self.chats.len() > MAX_TRACKED_CHATS {
+            self.chats.retain(|_, window| {
+                window.forget_expired(now);
+                !window.calls.is_empty()
+            });
+        }
+        self.global.forget_expired(now);
+        let chat = chat_id.map(|chat_id| {
+            let chat = self
+                .chats
+                .entry(chat_id)
+                .or_insert_with(|| Window::for_chat(chat_id));
+            chat.forget_expired(now);
+            chat
+        });
+        let global_needed = self.global.needed(cost, low_priority);
+        let chat_needed = chat
+            .as_ref()
+            .map_or(0, |chat| chat.needed(cost, low_priority));
+        let chat_free = chat.as_ref().map_or(0, |chat| chat.free());
+        if chat_free < chat_needed || self.global.free() < global_needed {
+            let chat_wait = chat.map_or(Duration::ZERO, |chat| chat.wait_time(now, chat_needed));
+            return Err(chat_wait.max(self.global.wait_time(now, global_needed)));
+        }
+        if let Some(chat) = chat {
+            chat.record(now, cost);
+        }
+        self.global.record(now, cost);
+        Ok(())
+    }
+}
+
+// Waits until the chat and the bot as a whole have room for a call. Used for everything that must arrive,
+// such as files and replies, so these calls are delayed under pressure but never dropped.
+async fn wait_turn(chat_id: Option<ChatId>, cost: usize) {
+    loop {
+        let wait = match BUDGETS.lock().unwrap().take(chat_id, cost, false) {
+            Ok(()) => return,
+            Err(wait) => wait,
+        };
+        trace!("Rate limited, waiting {} ms ...", wait.as_millis());
+        // A zero wait means another call took the room first, the loop tries again.
+        tokio::time::sleep(wait.max(Duration::from_millis(10))).await;
+    }
+}
+
+// Takes room for a low priority call such as a progress update, without waiting.
+// False means the call should be skipped, the next update carries the latest state anyway.
+fn try_turn(chat_id: ChatId) -> bool {
+    BUDGETS.lock().unwrap().take(Some(chat_id), 1, true).is_ok()
+}
+
+// The Bot API client for everything but receiving updates. Every call waits for its turn,
+// so that the limits hold no matter where the call is made.
+#[derive(Clone)]
+pub struct LimitedBot {
+    bot: Bot,
+}
+
+// A request that is sent once there is room for it. Parameters are set the same way as on the plain request.
+pub struct Limited<R> {
+    request: R,
+    chat_id: Option<ChatId>,
+    // Number of messages the request sends, Telegram counts every item of an album.
+    cost: usize,
+}
+
+impl<R> Limited<R> {
+    fn new(request: R, chat_id: Option<ChatId>, cost: usize) -> Self {
+        Self {
+            request,
+            chat_id,
+            cost,
+        }
+    }
+}
+
+impl<R: HasPayload> HasPayload for Limited<R> {
+    type Payload = R::Payload;
+
+    fn payload_mut(&mut self) -> &mut Self::Payload {
+        self.request.payload_mut()
+    }
+
+    fn payload_ref(&self) -> &Self::Payload {
+        self.request.payload_ref()
+    }
+}
+
+impl<R> IntoFuture for Limited<R>
+where
+    R: Request + Send + 'static,
+{
+    type Output = Result<Output<R>, R::Err>;
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(async move {
+            wait_turn(self.chat_id, self.cost).await;
+            self.request.await
+        })
+    }
+}
+
+impl LimitedBot {
+    pub fn new(bot: Bot) -> Self {
+        Self { bot }
+    }
+
+    pub fn send_message(
+        &self,
+        chat_id: ChatId,
+        text: impl Into<String>,
+    ) -> Limited<<Bot as Requester>::SendMessage> {
+        Limited::new(self.bot.send_message(chat_id, text), Some(chat_id), 1)
+    }
+
+    pub fn send_photo(
+        &self,
+        chat_id: ChatId,
+        photo: InputFile,
+    ) -> Limited<<Bot as Requester>::SendPhoto> {
+        Limited::new(self.bot.send_photo(chat_id, photo), Some(chat_id), 1)
+    }
+
+    pub fn send_audio(
+        &self,
+        chat_id: ChatId,
+        audio: InputFile,
+    ) -> Limited<<Bot as Requester>::SendAudio> {
+        Limited::new(self.bot.send_audio(chat_id, audio), Some(chat_id), 1)
+    }
+
+    pub fn send_video(
+        &self,
+        chat_id: ChatId,
+        video: InputFile,
+    ) -> Limited<<Bot as Requester>::SendVideo> {
+        Limited::new(self.bot.send_video(chat_id, video), Some(chat_id), 1)
+    }
+
+    pub fn send_animation(
+        &self,
+        chat_id: ChatId,
+        animation: InputFile,
+    ) -> Limited<<Bot as Requester>::SendAnimation> {
+        Limited::new(
+            self.bot.send_animation(chat_id, animation),
+            Some(chat_id),
+            1,
+        )
+    }
+
+    pub fn send_video_note(
+        &self,
+        chat_id: ChatId,
+        video_note: InputFile,
+    ) -> Limited<<Bot as Requester>::SendVideoNote> {
+        Limited::new(
+            self.bot.send_video_note(chat_id, video_note),
+            Some(chat_id),
+            1,
+        )
+    }
+
+    pub fn send_voice(
+        &self,
+        chat_id: ChatId,
+        voice: InputFile,
+    ) -> Limited<<Bot as Requester>::SendVoice> {
+        Limited::new(self.bot.send_voice(chat_id, voice), Some(chat_id), 1)
+    }
+
+    pub fn send_document(
+        &self,
+        chat_id: ChatId,
+        document: InputFile,
+    ) -> Limited<<Bot as Requester>::SendDocument> {
+        Limited::new(self.bot.send_document(chat_id, document), Some(chat_id), 1)
+    }
+
+    pub fn send_sticker(
+        &self,
+        chat_id: ChatId,
+        sticker: InputFile,
+    ) -> Limited<<Bot as Requester>::SendSticker> {
+        Limited::new(self.bot.send_sticker(chat_id, sticker), Some(chat_id), 1)
+    }
+
+    pub fn send_media_group(
+        &self,
+        chat_id: ChatId,
+        media: Vec<InputMedia>,
+    ) -> Limited<<Bot as Requester>::SendMediaGroup> {
+        let cost = media.len();
+        Limited::new(
+            self.bot.send_media_group(chat_id, media),
+            Some(chat_id),
+            cost,
+        )
+    }
+
+    pub fn edit_message_text(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        text: impl Into<String>,
+    ) -> Limited<<Bot as Requester>::EditMessageText> {
+        Limited::new(
+            self.bot.edit_message_text(chat_id, message_id, text),
+            Some(chat_id),
+            1,
+        )
+    }
+
+    // Low priority edit such as a progress update, None if it should be skipped.
+    pub fn try_edit_message_text(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        text: impl Into<String>,
+    ) -> Option<<Bot as Requester>::EditMessageText> {
+        try_turn(chat_id).then(|| self.bot.edit_message_text(chat_id, message_id, text))
+    }
+
+    // Chat actions are cosmetic, they are skipped the same way as progress updates.
+    pub fn try_send_chat_action(
+        &self,
+        chat_id: ChatId,
+        action: ChatAction,
+    ) -> Option<<Bot as Requester>::SendChatAction> {
+        try_turn(chat_id).then(|| self.bot.send_chat_action(chat_id, action))
+    }
+
+    pub fn delete_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> Limited<<Bot as Requester>::DeleteMessage> {
+        Limited::new(
+            self.bot.delete_message(chat_id, message_id),
+            Some(chat_id),
+            1,
+        )
+    }
+
+    pub fn answer_callback_query(
+        &self,
+        callback_query_id: CallbackQueryId,
+    ) -> Limited<<Bot as Requester>::AnswerCallbackQuery> {
+        Limited::new(self.bot.answer_callback_query(callback_query_id), None, 1)
+    }
+
+    pub fn set_my_commands(
+        &self,
+        commands: Vec<BotCommand>,
+    ) -> Limited<<Bot as Requester>::SetMyCommands> {
+        Limited::new(self.bot.set_my_commands(commands), None, 1)
+    }
+
+    pub fn get_file(&self, file_id: FileId) -> Limited<<Bot as Requester>::GetFile> {
+        Limited::new(self.bot.get_file(file_id), None, 1)
+    }
+
+    pub async fn download_file(
+        &self,
+        path: &str,
+        destination: &mut (dyn AsyncWrite + Unpin + Send),
+    ) -> Result<(), teloxide::DownloadError> {
+        wait_turn(None, 1).await;
+        self.bot.download_file(path, destination).await
+    }
+}
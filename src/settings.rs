@@ -0,0 +1,342 @@
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+use serde_type_name::type_name;
+use surrealdb::{Surreal, engine::remote::ws::Client as DbClient};
+use teloxide::prelude::*;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, User};
+
+use crate::MAX_UPLOAD_SIZE;
+use crate::database::table_name;
+use crate::i18n;
+use crate::task::caption;
+
+// Callback data of settings buttons starts with this prefix, so that the callback handler can tell them apart from media selection.
+pub const SETTINGS_CALLBACK_PREFIX: &str = "settings:";
+
+// What to do with files that are over Telegram's upload limit.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum OversizePolicy {
+    #[default]
+    Skip,
+    // Cut into sequential parts under the limit.
+    Split,
+    // Pick a format that fits the limit, re-encode if none does.
+    Fit,
+}
+
+impl OversizePolicy {
+    fn next(self) -> Self {
+        match self {
+            OversizePolicy::Skip => OversizePolicy::Split,
+            OversizePolicy::Split => OversizePolicy::Fit,
+            OversizePolicy::Fit => OversizePolicy::Skip,
+        }
+    }
+
+    fn label(self, language: &str) -> String {
+        let key = match self {
+            OversizePolicy::Skip => "settings.oversize.skip",
+            OversizePolicy::Split => "settings.oversize.split",
+            OversizePolicy::Fit => "settings.oversize.fit",
+        };
+        i18n::text(language, key)
+    }
+}
+
+// How subtitles are delivered with videos.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SubtitleMode {
+    #[default]
+    Off,
+    // Soft track inside the MP4 that players can toggle.
+    Embed,
+    // Rendered into the picture, for players without subtitle support.
+    Burn,
+    // Separate .srt documents sent next to the video.
+    Srt,
+}
+
+impl SubtitleMode {
+    fn next(self) -> Self {
+        match self {
+            SubtitleMode::Off => SubtitleMode::Embed,
+            SubtitleMode::Embed => SubtitleMode::Burn,
+            SubtitleMode::Burn => SubtitleMode::Srt,
+            SubtitleMode::Srt => SubtitleMode::Off,
+        }
+    }
+
+    fn label(self, language: &str) -> String {
+        let key = match self {
+            SubtitleMode::Off => "settings.subtitles.off",
+            SubtitleMode::Embed => "settings.subtitles.embed",
+            SubtitleMode::Burn => "settings.subtitles.burn",
+            SubtitleMode::Srt => "settings.subtitles.srt",
+        };
+        i18n::text(language, key)
+    }
+}
+
+// Markup of caption templates. Values from metadata are escaped for it, so a title can't break the markup.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CaptionFormat {
+    #[default]
+    Plain,
+    Html,
+    MarkdownV2,
+}
+
+impl CaptionFormat {
+    fn next(self) -> Self {
+        match self {
+            CaptionFormat::Plain => CaptionFormat::Html,
+            CaptionFormat::Html => CaptionFormat::MarkdownV2,
+            CaptionFormat::MarkdownV2 => CaptionFormat::Plain,
+        }
+    }
+
+    fn label(self, language: &str) -> String {
+        let key = match self {
+            CaptionFormat::Plain => "settings.caption_format.plain",
+            CaptionFormat::Html => "settings.caption_format.html",
+            CaptionFormat::MarkdownV2 => "settings.caption_format.markdown_v2",
+        };
+        i18n::text(language, key)
+    }
+}
+
+// Size limits a user can cycle through, in megabytes. The first one is Telegram's upload limit,
+// smaller ones are handy for forwarding files to chats with stricter limits.
+const SIZE_LIMIT_CHOICES_MB: [u64; 4] = [MAX_UPLOAD_SIZE / 1_000_000, 500, 200, 50];
+
+fn default_size_limit_mb() -> u64 {
+    SIZE_LIMIT_CHOICES_MB[0]
+}
+
+fn default_subtitle_languages() -> String {
+    String::from("en")
+}
+
+// Per-chat preferences. In private chats chat ID equals user ID, so these are effectively per-user.
+// Every field has a serde default so that records written by older versions keep deserializing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserSettings {
+    pub chat_id: ChatId,
+    #[serde(default)]
+    pub oversize_policy: OversizePolicy,
+    #[serde(default = "default_size_limit_mb")]
+    pub size_limit_mb: u64,
+    // Embed ID3 tags and cover art into audio and show track info in Telegram's player.
+    #[serde(default)]
+    pub tag_audio: bool,
+    #[serde(default)]
+    pub subtitle_mode: SubtitleMode,
+    // Comma separated yt-dlp --sub-langs value in order of preference, e.g. en,ru.
+    #[serde(default = "default_subtitle_languages")]
+    pub subtitle_languages: String,
+    // Fall back to automatically generated subtitles when there are no real ones.
+    #[serde(default)]
+    pub auto_subtitles: bool,
+    // Send audio and video playlists as albums of up to 10 files instead of one message per file.
+    #[serde(default)]
+    pub group_albums: bool,
+    // Caption of sent files with placeholders such as {title}. Empty means no caption.
+    #[serde(default)]
+    pub caption_template: String,
+    #[serde(default)]
+    pub caption_format: CaptionFormat,
+    // Button under sent files that opens the page they were downloaded from.
+    #[serde(default)]
+    pub source_button: bool,
+    // Language of the bot's messages. None follows the language of the user's Telegram app.
+    #[serde(default)]
+    pub language: Option<String>,
+}
+
+impl UserSettings {
+    pub fn new(chat_id: ChatId) -> Self {
+        Self {
+            chat_id,
+            oversize_policy: OversizePolicy::default(),
+            size_limit_mb: default_size_limit_mb(),
+            tag_audio: false,
+            subtitle_mode: SubtitleMode::default(),
+            subtitle_languages: default_subtitle_languages(),
+            auto_subtitles: false,
+            group_albums: false,
+            caption_template: String::new(),
+            caption_format: CaptionFormat::default(),
+            source_button: false,
+            language: None,
+        }
+    }
+
+    // Returns defaults if the chat has never changed anything.
+    #[tracing::instrument(skip(db))]
+    pub async fn from_db(
+        chat_id: ChatId,
+        db: Surreal<DbClient>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let defaults = Self::new(chat_id);
+        let table_name = table_name(type_name(&defaults)?);
+        let settings: Option<Self> = db.select((table_name, chat_id.to_string())).await?;
+        Ok(settings.unwrap_or(defaults))
+    }
+
+    // One record per chat, keyed by chat ID.
+    #[tracing::instrument(skip_all, fields(chat_id = %self.chat_id))]
+    pub async fn intodb(&self, db: Surreal<DbClient>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        trace!("Saving settings ...");
+        let table_name = table_name(type_name(self)?);
+        let _: Option<Self> = db
+            .upsert((table_name, self.chat_id.to_string()))
+            .content(self.clone())
+            .await?;
+        Ok(())
+    }
+
+    // Never above what Telegram accepts, even if a limit was stored by a misconfigured instance.
+    pub fn size_limit_bytes(&self) -> u64 {
+        (self.size_limit_mb * 1_000_000).min(MAX_UPLOAD_SIZE)
+    }
+
+    // Language for messages to this chat, the user is whoever the bot is replying to.
+    pub fn language_for(&self, user: Option<&User>) -> &'static str {
+        i18n::pick(
+            self.language.as_deref(),
+            user.and_then(|user| user.language_code.as_deref()),
+        )
+    }
+
+    // Accepts yt-dlp language codes and patterns like en.* separated by commas.
+    pub fn set_subtitle_languages(
+        &mut self,
+        languages: &str,
+        language: &str,
+    ) -> Result<(), String> {
+        let languages = languages.trim().replace(' ', "");
+        let is_valid = !languages.is_empty()
+            && languages
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.*,".contains(c));
+        if !is_valid {
+            return Err(i18n::text_with(
+                language,
+                "not_language_codes",
+                &[("languages", &languages)],
+            ));
+        }
+        self.subtitle_languages = languages;
+        Ok(())
+    }
+
+    // "off" removes the caption. Only known placeholders are accepted, so that typos don't end up in captions.
+    pub fn set_caption_template(&mut self, template: &str, language: &str) -> Result<(), String> {
+        let template = template.trim();
+        if template.is_empty() || template.eq_ignore_ascii_case("off") {
+            self.caption_template = String::new();
+            return Ok(());
+        }
+        if template.chars().count() > caption::CAPTION_MAX_LENGTH {
+            return Err(i18n::text_with(
+                language,
+                "caption_template_too_long",
+                &[("length", &caption::CAPTION_MAX_LENGTH)],
+            ));
+        }
+        if let Some(placeholder) = caption::unknown_placeholder(template) {
+            return Err(i18n::text_with(
+                language,
+                "unknown_placeholder",
+                &[
+                    ("placeholder", &format!("{{{placeholder}}}")),
+                    ("placeholders", &caption::placeholders_list()),
+                ],
+            ));
+        }
+        self.caption_template = template.to_string();
+        Ok(())
+    }
+
+    // Summary of the settings that can't be changed with buttons.
+    pub fn describe(&self, language: &str) -> String {
+        let caption_template = match self.caption_template.as_str() {
+            "" => i18n::text(language, "settings.caption_none"),
+            template => template.to_string(),
+        };
+        i18n::text_with(
+            language,
+            "settings_summary",
+            &[
+                ("languages", &self.subtitle_languages),
+                ("caption", &caption_template),
+                ("placeholders", &caption::placeholders_list()),
+            ],
+        )
+    }
+
+    // Applies a pressed settings button. Returns false for unknown callback data.
+    pub fn apply_callback_data(&mut self, data: &str) -> bool {
+        match data.strip_prefix(SETTINGS_CALLBACK_PREFIX) {
+            Some("oversize") => self.oversize_policy = self.oversize_policy.next(),
+            Some("size_limit") => {
+                let position = SIZE_LIMIT_CHOICES_MB
+                    .iter()
+                    .position(|choice| *choice == self.size_limit_mb)
+                    .unwrap_or_default();
+                self.size_limit_mb =
+                    SIZE_LIMIT_CHOICES_MB[(position + 1) % SIZE_LIMIT_CHOICES_MB.len()];
+            }
+            Some("tag_audio") => self.tag_audio = !self.tag_audio,
+            Some("subtitle_mode") => self.subtitle_mode = self.subtitle_mode.next(),
+            Some("auto_subtitles") => self.auto_subtitles = !self.auto_subtitles,
+            Some("group_albums") => self.group_albums = !self.group_albums,
+            Some("caption_format") => self.caption_format = self.caption_format.next(),
+            Some("source_button") => self.source_button = !self.source_button,
+            // Cycles through the languages with a catalog and back to following Telegram.
+            Some("language") => {
+                let next = match &self.language {
+                    None => 0,
+                    Some(language) => i18n::LANGUAGES
+                        .iter()
+                        .position(|candidate| candidate == language)
+                        .map_or(0, |position| position + 1),
+                };
+                self.language = i18n::LANGUAGES
+                    .get(next)
+                    .map(|language| language.to_string());
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    // Every button shows the current value and cycles to the next one when pressed.
+    pub fn make_keyboard(&self, language: &str) -> InlineKeyboardMarkup {
+        let button = |key: &str, value: &dyn std::fmt::Display| {
+            let text = i18n::text_with(language, &format!("settings.{key}"), &[("value", value)]);
+            InlineKeyboardButton::callback(text, format!("{SETTINGS_CALLBACK_PREFIX}{key}"))
+        };
+        let on_off =
+            |value: bool| i18n::text(language, if value { "settings.on" } else { "settings.off" });
+        let language_label = match &self.language {
+            Some(chosen) => i18n::text(chosen, "language_name"),
+            None => i18n::text(language, "settings.language.auto"),
+        };
+        InlineKeyboardMarkup::new(vec![
+            vec![button("size_limit", &self.size_limit_mb)],
+            vec![button("oversize", &self.oversize_policy.label(language))],
+            vec![button("tag_audio", &on_off(self.tag_audio))],
+            vec![button("subtitle_mode", &self.subtitle_mode.label(language))],
+            vec![button("auto_subtitles", &on_off(self.auto_subtitles))],
+            vec![button("group_albums", &on_off(self.group_albums))],
+            vec![button(
+                "caption_format",
+                &self.caption_format.label(language),
+            )],
+            vec![button("source_button", &on_off(self.source_button))],
+            vec![button("language", &language_label)],
+        ])
+    }
+}
@@ -0,0 +1,187 @@
+use teloxide::types::ParseMode;
+use teloxide::utils::{html, markdown};
+
+use super::fileinfo::FileInfo;
+use crate::settings::CaptionFormat;
+
+// Telegram limits captions to 1024 characters.
+pub const CAPTION_MAX_LENGTH: usize = 1024;
+// Long values are cut, so that a title that is actually a description leaves room for the rest of the template.
+const VALUE_MAX_LENGTH: usize = 256;
+
+const PLACEHOLDERS: [&str; 6] = [
+    "title",
+    "uploader",
+    "duration",
+    "upload_date",
+    "url",
+    "playlist_index",
+];
+
+pub fn placeholders_list() -> String {
+    PLACEHOLDERS
+        .iter()
+        .map(|placeholder| format!("{{{placeholder}}}"))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+// Returns the first {name} in the template that is not a known placeholder.
+pub fn unknown_placeholder(template: &str) -> Option<String> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = start + rest[start..].find('}')?;
+        let name = &rest[start + 1..end];
+        if !PLACEHOLDERS.contains(&name) {
+            return Some(name.to_string());
+        }
+        rest = &rest[end + 1..];
+    }
+    None
+}
+
+// Plain text is sent as HTML with everything escaped, so that every caption can be sent with a parse mode.
+pub fn parse_mode(format: CaptionFormat) -> ParseMode {
+    match format {
+        CaptionFormat::Plain | CaptionFormat::Html => ParseMode::Html,
+        CaptionFormat::MarkdownV2 => ParseMode::MarkdownV2,
+    }
+}
+
+// Makes text from metadata or from the bot itself safe to put into a caption of this format.
+pub fn escape(text: &str, format: CaptionFormat) -> String {
+    match format {
+        CaptionFormat::Plain | CaptionFormat::Html => html::escape(text),
+        CaptionFormat::MarkdownV2 => markdown::escape(text),
+    }
+}
+
+// Fills the template with the metadata of the file. Missing values are left empty.
+// A caption that doesn't fit falls back to the title, since cutting markup could break it.
+pub fn render(template: &str, format: CaptionFormat, info: &FileInfo) -> String {
+    // Templates of markup formats are markup already, only plain text ones need escaping.
+    let literal = |text: &str| match format {
+        CaptionFormat::Plain => html::escape(text),
+        CaptionFormat::Html | CaptionFormat::MarkdownV2 => text.to_string(),
+    };
+    let mut caption = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}').map(|length| start + length) else {
+            break;
+        };
+        caption.push_str(&literal(&rest[..start]));
+        match value(&rest[start + 1..end], info) {
+            Some(value) => caption.push_str(&escape(&truncate(&value), format)),
+            None => caption.push_str(&literal(&rest[start..=end])),
+        }
+        rest = &rest[end + 1..];
+    }
+    caption.push_str(&literal(rest));
+    if caption.chars().count() > CAPTION_MAX_LENGTH {
+        debug!("Caption is too long, using the title instead");
+        return escape(&truncate(&info.title.clone().unwrap_or_default()), format);
+    }
+    caption
+}
+
+// None for unknown placeholders, an empty string for values the site didn't provide.
+fn value(placeholder: &str, info: &FileInfo) -> Option<String> {
+    let value = match placeholder {
+        "title" => info.title.clone().or_else(|| info.track.clone()),
+        "uploader" => info.uploader.clone(),
+        "duration" => info.duration.map(format_duration),
+        "upload_date" => info.upload_date.as_deref().map(format_date),
+        "url" => info.webpage_url.clone(),
+        "playlist_index" => info.playlist_index.map(|index| index.to_string()),
+        _ => return None,
+    };
+    Some(value.unwrap_or_default())
+}
+
+fn truncate(text: &str) -> String {
+    text.chars().take(VALUE_MAX_LENGTH).collect()
+}
+
+// 4:05 or 1:02:03.
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    match hours {
+        0 => format!("{minutes}:{seconds:02}"),
+        _ => format!("{hours}:{minutes:02}:{seconds:02}"),
+    }
+}
+
+// yt-dlp gives dates as YYYYMMDD, captions show them as YYYY-MM-DD.
+fn format_date(date: &str) -> String {
+    match (date.get(..4), date.get(4..6), date.get(6..8)) {
+        (Some(year), Some(month), Some(day)) if date.len() == 8 => {
+            format!("{year}-{month}-{day}")
+        }
+        _ => date.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> FileInfo {
+        FileInfo {
+            title: Some("a_b*c.".to_string()),
+            uploader: Some("<Tom & Jerry>".to_string()),
+            duration: Some(3723.4),
+            upload_date: Some("20240131".to_string()),
+            ..FileInfo::default()
+        }
+    }
+
+    #[test]
+    fn markdown_v2_escapes_values_but_not_the_template() {
+        let caption = render(
+            "*{title}* by {uploader}",
+            CaptionFormat::MarkdownV2,
+            &info(),
+        );
+        assert_eq!(caption, r"*a\_b\*c\.* by <Tom & Jerry\>");
+    }
+
+    #[test]
+    fn html_escapes_values_but_not_the_template() {
+        let caption = render("<b>{title}</b> {uploader}", CaptionFormat::Html, &info());
+        assert_eq!(caption, "<b>a_b*c.</b> &lt;Tom &amp; Jerry&gt;");
+    }
+
+    #[test]
+    fn plain_text_escapes_everything() {
+        let caption = render("<{title}> {uploader}", CaptionFormat::Plain, &info());
+        assert_eq!(caption, "&lt;a_b*c.&gt; &lt;Tom &amp; Jerry&gt;");
+    }
+
+    #[test]
+    fn formats_values() {
+        let caption = render(
+            "{duration} {upload_date} {url}{unknown}",
+            CaptionFormat::Plain,
+            &info(),
+        );
+        assert_eq!(caption, "1:02:03 2024-01-31 {unknown}");
+    }
+
+    #[test]
+    fn too_long_captions_fall_back_to_the_title() {
+        let template = "{title}".repeat(CAPTION_MAX_LENGTH);
+        let caption = render(&template, CaptionFormat::MarkdownV2, &info());
+        assert_eq!(caption, r"a\_b\*c\.");
+    }
+
+    #[test]
+    fn finds_unknown_placeholders() {
+        assert_eq!(unknown_placeholder("{title} {url}"), None);
+        assert_eq!(
+            unknown_placeholder("{title} {views}"),
+            Some("views".to_string())
+        );
+    }
+}
@@ -1,22 +1,37 @@
+use super::caption::{self, CAPTION_MAX_LENGTH};
+use super::fileinfo::{FileInfo, metadata_path};
 use super::id::TaskId;
-use super::mediatype::MediaType;
+use super::progress::{ProgressReader, SharedProgress, SharedUploadProgress, UploadProgress};
+use super::retry::{SEND_ATTEMPTS, is_markup_error, retry_delay};
 use super::stats::*;
+use super::timerange::TimeRange;
 use super::traits::*;
+use crate::admins;
+use crate::downloader::error::{DownloadError, DownloadErrorKind};
+use crate::downloader::{self, DownloadJob, Downloader, SharedProxy};
+use crate::ffmpeg::{
+    burn_subtitles, fit_to_size, split_into_parts, to_animation, to_sticker, to_video_note,
+};
+use crate::i18n;
 use crate::misc::*;
+use crate::profiles::{MediaProfile, PostProcess, ProfileId, SendMethod};
+use crate::proxies::{PROXIES, redact};
+use crate::ratelimit::LimitedBot;
+use crate::settings::{CaptionFormat, OversizePolicy, SubtitleMode, UserSettings};
 use crate::task::cancellation::TASK_REGISTRY;
 use crate::trackedmessage::TrackedMessage;
-use glob::glob;
 use humantime::format_rfc3339_seconds as timestamp;
-use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::path::PathBuf;
-use std::time::SystemTime;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 use surrealdb::{Surreal, engine::remote::ws::Client as DbClient};
 use teloxide::prelude::*;
-use teloxide::types::InputFile;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::process::Command;
+use teloxide::types::{
+    ChatAction, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, InputMedia, InputMediaAudio,
+    InputMediaPhoto, InputMediaVideo, UserId,
+};
 use tokio_util::sync::CancellationToken;
 use url::Url;
 use tracing::Instrument;
@@ -26,11 +41,30 @@ type HandlerResult = Result<(), Box<dyn Error + Send + Sync>>;
 pub struct TaskDownload {
     pub task_id: TaskId,
     pub chat_id: ChatId,
-    pub media_type: MediaType,
+    // Records written before profiles existed store a media type, which is converted to a profile ID.
+    #[serde(alias = "media_type")]
+    pub profile: ProfileId,
     // Option because at the intermediate stage WaitingForUrl it is known that the task is Download but initial URL is None.
     pub url: Option<Url>,
+    // Only this part of the media is downloaded if set.
+    #[serde(default)]
+    pub time_range: Option<TimeRange>,
+    // Who sent the URL, their private cookies are used for it.
+    #[serde(default)]
+    pub user_id: Option<UserId>,
+    // Chosen when the task starts running, replaced by the proxy of the latest attempt once the download is over.
+    // Stored without credentials, see PROXIES.with_credentials().
+    #[serde(default)]
+    pub proxy: Option<Url>,
+    // Language of the messages about the task, picked when it starts running.
+    #[serde(default = "default_language")]
+    pub language: String,
     //started_at: Utc,
 }
+
+fn default_language() -> String {
+    i18n::DEFAULT_LANGUAGE.to_string()
+}
 impl HasTaskId for TaskDownload {
     fn task_id(&self) -> TaskId {
         self.task_id
@@ -49,23 +83,56 @@ impl TaskDownload {
     pub fn set_url(&mut self, url: Url) {
         self.url = Some(url);
     }
-    fn media_type(&self) -> MediaType {
-        self.media_type
+    pub fn set_time_range(&mut self, time_range: Option<TimeRange>) {
+        self.time_range = time_range;
+    }
+    pub fn set_user_id(&mut self, user_id: Option<UserId>) {
+        self.user_id = user_id;
+    }
+    pub fn set_proxy(&mut self, proxy: Option<Url>) {
+        self.proxy = proxy;
+    }
+    pub fn set_language(&mut self, language: &str) {
+        self.language = language.to_string();
+    }
+    // The profile may have been removed from the configuration since the task was created.
+    fn profile(&self) -> Result<&'static MediaProfile, String> {
+        MediaProfile::find(&self.profile).ok_or_else(|| {
+            i18n::text_with(
+                &self.language,
+                "profile_unavailable",
+                &[("profile", &self.profile)],
+            )
+        })
     }
     pub fn to_task_stats(&self) -> TaskStats {
         TaskStats {
             task_id: self.task_id(),
             chat_id: self.chat_id(),
-            media_type: Some(self.media_type()),
+            profile: Some(self.profile.clone()),
             // This unwrap is safe because TaskState::Running is not possible without URL.
             url: self.url(),
+            time_range: self.time_range,
+            proxy: self.proxy.as_ref().map(redact),
         }
     }
     #[tracing::instrument(skip_all)]
-    pub async fn process_request(&self, bot: Bot, db: Surreal<DbClient>) -> HandlerResult {
+    pub async fn process_request(
+        &mut self,
+        bot: LimitedBot,
+        db: Surreal<DbClient>,
+    ) -> HandlerResult {
         debug!("Processing request ...");
+        let text = match self.time_range {
+            Some(time_range) => i18n::text_with(
+                &self.language,
+                "preparing_clip_download",
+                &[("time_range", &time_range)],
+            ),
+            None => i18n::text(&self.language, "preparing_download"),
+        };
         let tracked_messages = self
-            .send_and_remember_msg("Preparing the download...", bot.clone(), db.clone())
+            .send_and_remember_msg(&text, bot.clone(), db.clone())
             .await?;
 
         let last_message = tracked_messages[0].clone();
@@ -76,8 +143,17 @@ impl TaskDownload {
         match downloads_result {
             Err(error) => {
                 warn!("{error}");
-                self.send_and_remember_msg(&error.to_string(), bot.clone(), db)
-                    .await?;
+                let mut text = error.to_string();
+                if let Some(download_error) = error.downcast_ref::<DownloadError>() {
+                    warn!("{:?}: {}", download_error.kind, download_error.log.trim());
+                    text = download_error.message(&self.language);
+                    // The raw log helps to fix the cause, but it is noise for everyone else.
+                    let is_admin = self.user_id.is_some_and(admins::is_admin);
+                    if is_admin && !download_error.log.trim().is_empty() {
+                        text = format!("{text}\n\n{}", log_tail(&download_error.log));
+                    }
+                }
+                self.send_and_remember_msg(&text, bot.clone(), db).await?;
                 Err(error)
             }
             Ok(_) => {
@@ -94,82 +170,316 @@ impl TaskDownload {
         }
     }
     #[tracing::instrument(skip_all)]
-    async fn send_file(&self, path: &PathBuf, bot: Bot, db: Surreal<DbClient>) -> HandlerResult {
-        let file = InputFile::file(path);
+    async fn send_file(
+        &self,
+        outgoing_file: &OutgoingFile,
+        profile: &MediaProfile,
+        settings: &UserSettings,
+        upload_progress: &SharedUploadProgress,
+        bot: LimitedBot,
+        db: Surreal<DbClient>,
+    ) -> HandlerResult {
+        let path = &outgoing_file.path;
         let filename_display = path.display().to_string();
-        let max_retries = 10;
-
-        Ok(for attempt in 1..=max_retries {
-            let result = match self.media_type() {
-                MediaType::Mp3 => bot.send_audio(self.chat_id(), file.clone()).await,
-                MediaType::Mp4 => {
-                    // The backend downloads videos in .mp4 and places .jpg thumbnail next to the video in the same folder with the same base name.
-                    let video_metadata = get_video_metadata(path);
-                    let mut thumbnail_path = path.with_extension("jpg");
-                    let thumbnail_file = tokio::task::spawn_blocking(move || {
-                        compress_thumbnail(&mut thumbnail_path).unwrap();
-                        InputFile::file(thumbnail_path)
-                    })
-                    .await
-                    .unwrap();
-                    bot.send_video(self.chat_id(), file.clone())
-                        .thumbnail(thumbnail_file)
-                        .duration(video_metadata.duration)
-                        .height(video_metadata.height)
-                        .width(video_metadata.width)
-                        .await
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let info = &outgoing_file.info;
+        let mut caption = caption_of(outgoing_file, profile, settings);
+        let mut parse_mode = caption::parse_mode(settings.caption_format);
+        let mut caption_as_plain_text = settings.caption_format == CaptionFormat::Plain;
+        let reply_markup = source_button(info, settings, &self.language);
+        // Audio gets a thumbnail and track info only if the user wants tagged audio.
+        let tag_audio = settings.tag_audio && profile.send == SendMethod::Audio;
+        // Thumbnail is compressed once for all attempts.
+        let wants_thumbnail = tag_audio
+            || matches!(
+                profile.send,
+                SendMethod::Video
+                    | SendMethod::Document
+                    | SendMethod::Animation
+                    | SendMethod::VideoNote
+            );
+        let thumbnail_file = match outgoing_file.thumbnail.clone() {
+            Some(thumbnail_path) if wants_thumbnail => load_thumbnail(thumbnail_path).await?,
+            _ => None,
+        };
+
+        // Failed attempts are reported in a single message that is edited on every retry.
+        let mut status_message: Option<TrackedMessage> = None;
+        // This unwrap should work as long as the registry is implemented correctly
+        let task_cancellation_token = TASK_REGISTRY.get_token(self.task_id()).unwrap();
+        let mut attempt = 1;
+        loop {
+            // A new reader for every attempt, because teloxide buffers a reader in memory when it is shared.
+            let file = open_for_upload(path, &file_name, upload_progress).await?;
+            let request = async {
+                match profile.send {
+                    SendMethod::Audio => {
+                        let mut request = bot
+                            .send_audio(self.chat_id(), file)
+                            .caption(caption.clone())
+                            .parse_mode(parse_mode);
+                        if tag_audio {
+                            request = request.duration(get_video_metadata(path).duration);
+                            if let Some(title) = info.track_title() {
+                                request = request.title(title);
+                            }
+                            if let Some(performer) = info.performer() {
+                                request = request.performer(performer);
+                            }
+                            if let Some(thumbnail_file) = thumbnail_file.clone() {
+                                request = request.thumbnail(thumbnail_file);
+                            }
+                        }
+                        if let Some(reply_markup) = reply_markup.clone() {
+                            request = request.reply_markup(reply_markup);
+                        }
+                        request.await
+                    }
+                    SendMethod::Video => {
+                        let video_metadata = get_video_metadata(path);
+                        let mut request = bot
+                            .send_video(self.chat_id(), file)
+                            .caption(caption.clone())
+                            .parse_mode(parse_mode)
+                            .duration(video_metadata.duration)
+                            .height(video_metadata.height)
+                            .width(video_metadata.width);
+                        if let Some(thumbnail_file) = thumbnail_file.clone() {
+                            request = request.thumbnail(thumbnail_file);
+                        }
+                        if let Some(reply_markup) = reply_markup.clone() {
+                            request = request.reply_markup(reply_markup);
+                        }
+                        request.await
+                    }
+                    SendMethod::Animation => {
+                        let video_metadata = get_video_metadata(path);
+                        let mut request = bot
+                            .send_animation(self.chat_id(), file)
+                            .caption(caption.clone())
+                            .parse_mode(parse_mode)
+                            .duration(video_metadata.duration)
+                            .height(video_metadata.height)
+                            .width(video_metadata.width);
+                        if let Some(thumbnail_file) = thumbnail_file.clone() {
+                            request = request.thumbnail(thumbnail_file);
+                        }
+                        if let Some(reply_markup) = reply_markup.clone() {
+                            request = request.reply_markup(reply_markup);
+                        }
+                        request.await
+                    }
+                    // Video notes can't have captions.
+                    SendMethod::VideoNote => {
+                        let mut request = bot
+                            .send_video_note(self.chat_id(), file)
+                            .duration(get_video_metadata(path).duration)
+                            .length(VIDEO_NOTE_LENGTH);
+                        if let Some(thumbnail_file) = thumbnail_file.clone() {
+                            request = request.thumbnail(thumbnail_file);
+                        }
+                        if let Some(reply_markup) = reply_markup.clone() {
+                            request = request.reply_markup(reply_markup);
+                        }
+                        request.await
+                    }
+                    // Stickers can't have captions, users add them to packs themselves.
+                    SendMethod::Sticker => {
+                        let mut request = bot.send_sticker(self.chat_id(), file);
+                        if let Some(reply_markup) = reply_markup.clone() {
+                            request = request.reply_markup(reply_markup);
+                        }
+                        request.await
+                    }
+                    SendMethod::Document => {
+                        let mut request = bot
+                            .send_document(self.chat_id(), file)
+                            .caption(caption.clone())
+                            .parse_mode(parse_mode);
+                        if let Some(thumbnail_file) = thumbnail_file.clone() {
+                            request = request.thumbnail(thumbnail_file);
+                        }
+                        if let Some(reply_markup) = reply_markup.clone() {
+                            request = request.reply_markup(reply_markup);
+                        }
+                        request.await
+                    }
+                    SendMethod::Voice => {
+                        let mut request = bot
+                            .send_voice(self.chat_id(), file)
+                            .caption(caption.clone())
+                            .parse_mode(parse_mode);
+                        if let Some(reply_markup) = reply_markup.clone() {
+                            request = request.reply_markup(reply_markup);
+                        }
+                        request.await
+                    }
+                    // Albums are sent by send_album(), single items of a post are either images or videos.
+                    SendMethod::Album if is_video_file(path) => {
+                        bot.send_video(self.chat_id(), file)
+                            .caption(caption.clone())
+                            .parse_mode(parse_mode)
+                            .await
+                    }
+                    SendMethod::Album => {
+                        bot.send_photo(self.chat_id(), file)
+                            .caption(caption.clone())
+                            .parse_mode(parse_mode)
+                            .await
+                    }
                 }
-                MediaType::Voice => bot.send_voice(self.chat_id(), file.clone()).await,
             };
+            let action = chat_action(profile.send, path);
+            let result = with_chat_action(&bot, self.chat_id(), action, request).await;
 
-            match result {
+            let error = match result {
                 Ok(_) => {
                     info!("File '{filename_display}' sent successfully.");
                     return Ok(());
                 }
-                Err(error) => {
-                    sleep(10).await;
-                    let error_text = format!(
-                        "Attempt {attempt}/{max_retries} at sending '{filename_display}' failed: {error}"
-                    );
-                    warn!("{}", error_text);
-
-                    if attempt < max_retries {
-                        self.send_and_remember_msg(&error_text, bot.clone(), db.clone())
-                            .await?;
+                Err(error) => error,
+            };
+            warn!(
+                "Attempt {attempt}/{SEND_ATTEMPTS} at sending '{filename_display}' failed: {error}"
+            );
+            // Markup of the user's template can't be checked in advance, the file is sent once more
+            // with the whole caption as plain text instead of failing every upload.
+            if is_markup_error(&error) && !caption_as_plain_text {
+                warn!("Caption markup was rejected, sending the caption as plain text ...");
+                let plain_settings = UserSettings {
+                    caption_format: CaptionFormat::Plain,
+                    ..settings.clone()
+                };
+                caption = caption_of(outgoing_file, profile, &plain_settings);
+                parse_mode = caption::parse_mode(CaptionFormat::Plain);
+                caption_as_plain_text = true;
+                continue;
+            }
+            let delay = match retry_delay(&error, attempt) {
+                Some(delay) if attempt < SEND_ATTEMPTS => delay,
+                Some(_) => {
+                    return Err(i18n::text_with(
+                        &self.language,
+                        "send_failed",
+                        &[
+                            ("file", &file_name),
+                            ("attempts", &SEND_ATTEMPTS),
+                            ("error", &error),
+                        ],
+                    )
+                    .into());
+                }
+                None => {
+                    return Err(i18n::text_with(
+                        &self.language,
+                        "cannot_send",
+                        &[("file", &file_name), ("error", &error)],
+                    )
+                    .into());
+                }
+            };
+            let status_text = i18n::text_with(
+                &self.language,
+                "send_attempt_failed",
+                &[
+                    ("attempt", &attempt),
+                    ("attempts", &SEND_ATTEMPTS),
+                    ("file", &file_name),
+                    ("error", &error),
+                    ("seconds", &delay.as_secs()),
+                ],
+            );
+            match &status_message {
+                Some(status_message) => match bot.try_edit_message_text(
+                    status_message.chat_id(),
+                    status_message.message_id,
+                    &status_text,
+                ) {
+                    Some(request) => {
+                        if let Err(e) = request.await {
+                            warn!("Failed to update message: {}", e);
+                        }
+                    }
+                    // The status is not worth delaying the retry for.
+                    None => {
+                        trace!(
+                            "Skipping update of message {} ...",
+                            status_message.message_id
+                        );
                     }
-                    //Err(format!("Failed to send file after {max_retries} attempts: {filename_display}").into())
+                },
+                None => {
+                    status_message = self
+                        .send_and_remember_msg(&status_text, bot.clone(), db.clone())
+                        .await?
+                        .into_iter()
+                        .next();
                 }
             }
-        })
+            tokio::select! {
+                _ = task_cancellation_token.cancelled() => {
+                    return Err(DownloadError::new(DownloadErrorKind::Cancelled).into());
+                }
+                _ = tokio::time::sleep(delay) => {}
+            }
+            attempt += 1;
+        }
     }
     #[tracing::instrument(skip_all, fields(task_id = %self.task_id()))]
     async fn download_and_send_files(
-        &self,
+        &mut self,
         last_message: TrackedMessage,
-        bot: Bot,
+        bot: LimitedBot,
         db: Surreal<DbClient>,
     ) -> HandlerResult {
+        // Resolved before the poller is spawned, it runs until it is cancelled below.
+        let profile = self.profile()?;
+        let settings = UserSettings::from_db(self.chat_id(), db.clone()).await?;
         let poller_cancellation_token_tx = CancellationToken::new();
         let poller_cancellation_token_rx = poller_cancellation_token_tx.clone();
         let bot_for_poller = bot.clone();
+        let progress = SharedProgress::default();
+        let progress_for_poller = progress.clone();
+        let upload_progress = SharedUploadProgress::default();
+        let upload_progress_for_poller = upload_progress.clone();
+        let time_range = self.time_range;
+        let language = self.language.clone();
         let poller_handle = tokio::spawn(async move {
             if let Err(e) = last_message
                 .directory_size_poller_and_message_updater(
                     poller_cancellation_token_rx,
                     bot_for_poller,
+                    progress_for_poller,
+                    upload_progress_for_poller,
+                    time_range,
+                    language,
                 )
                 .await
             {
                 warn!("{}", e);
             }
         });
-        let yt_dlp_args = generate_yt_dlp_args(self.media_type, self.url.clone().unwrap());
         // UUID is used to name path so that a second concurrent Tokio task can gather info from that path.
         let absolute_destination_path = &construct_destination_path(self.task_id().to_string());
+        let downloader = downloader::select(
+            profile,
+            self.url.clone().unwrap(),
+            self.time_range,
+            settings.clone(),
+            self.user_id,
+            self.proxy
+                .as_ref()
+                .map(|proxy| PROXIES.with_credentials(proxy)),
+        )
+        .await;
         // Cleanup here is needed in case the task was respawned after interruption.
         // We need to start from 0 because existing artifacts result in corrupted downloads.
         cleanup(absolute_destination_path.into());
+        // Remove the directory on every exit path, including early returns and panics.
+        let _cleanup_guard = scopeguard::guard(PathBuf::from(absolute_destination_path), cleanup);
         let path = PathBuf::from(absolute_destination_path);
         // This unwrap should work as long as the registry is implemented correctly
         let task_cancellation_token = TASK_REGISTRY.get_token(self.task_id()).unwrap();
@@ -177,277 +487,841 @@ impl TaskDownload {
             "th_downloader",
             task_id = %self.task_id(),
         );
-        let downloader_handle = tokio::spawn(
-            async move {
-                yt_dlp(path, yt_dlp_args, task_cancellation_token).await
-            }.instrument(downloader_span)
-        );
-        let ytdresult = downloader_handle.await.unwrap();
-        let mut paths: Vec<PathBuf> = Vec::new();
-        let regex = Regex::new(r"(.*)(\.opus)").unwrap();
-        let filepaths = glob(&format!(
-            "{absolute_destination_path}/*{}",
-            self.media_type().as_str()
-        ))?;
-        for entry in filepaths {
-            if let Ok(mut file_path) = entry {
-                let filename = file_path.to_str().unwrap();
-                // Local Telegram API allows bots sending only files under 2 GB.
-                let filesize = file_path.metadata()?.len();
-                if filesize < 2_000_000_000 {
-                    // Rename .opus into .ogg because Telegram requires so to display wave pattern.
-                    if let Some(captures) = regex.captures(filename) {
-                        let oldname = captures.get(0).unwrap().as_str();
-                        let timestamp = timestamp(SystemTime::now())
-                            .to_string()
-                            .replace(":", "-")
-                            .replace("T", "_")
-                            .replace("Z", "");
-                        // Filename formatting that is used by Telegram when sending voice messages.
-                        let newname = format!("{absolute_destination_path}/audio_{timestamp}.ogg");
-                        std::fs::rename(oldname, &newname)?;
-                        file_path = PathBuf::from(newname);
+        // Finished files are handed over through a bounded channel. When it is full, the stdout reader
+        // stops reading, yt-dlp blocks on its stdout pipe and the download pauses until a file is sent.
+        // This bounds peak disk usage to a few items regardless of playlist length.
+        let (finished_files_tx, mut finished_files_rx) =
+            tokio::sync::mpsc::channel::<FileInfo>(FINISHED_FILES_BUFFER);
+        // Child token to stop the downloader if sending fails, without cancelling the whole task.
+        let downloader_cancellation_token = task_cancellation_token.child_token();
+        let downloader_cancellation_token_tx = downloader_cancellation_token.clone();
+        let proxy = SharedProxy::new(Mutex::new(self.proxy.clone()));
+        let job = DownloadJob {
+            directory: path,
+            cancellation_token: downloader_cancellation_token,
+            progress: progress.clone(),
+            finished_files_tx,
+            proxy: proxy.clone(),
+        };
+        let downloader_handle =
+            tokio::spawn(async move { downloader.download(job).await }.instrument(downloader_span));
+        // Send and delete files one by one while the download continues.
+        let mut sent_count: usize = 0;
+        let mut sending_result: HandlerResult = Ok(());
+        // Files of a post are collected until the post ends or there are enough to fill an album.
+        let mut album: Vec<FileInfo> = Vec::new();
+        // Same for playlists if the user wants them as albums.
+        let group_albums =
+            settings.group_albums && matches!(profile.send, SendMethod::Audio | SendMethod::Video);
+        let mut playlist_album: Vec<OutgoingFile> = Vec::new();
+        while let Some(file_info) = finished_files_rx.recv().await {
+            if let SendMethod::Album = profile.send {
+                // Files of a post arrive one after another, the first file of the next post closes the album.
+                let next_album = match album.first() {
+                    Some(first) if first.post_key() != file_info.post_key() => vec![file_info],
+                    _ => {
+                        album.push(file_info);
+                        if album.len() < ALBUM_MAX_ITEMS {
+                            continue;
+                        }
+                        Vec::new()
                     }
-                    paths.push(file_path);
-                } else {
-                    trace!("Skipping large file {filename}");
+                };
+                sending_result = self
+                    .send_album(
+                        &mut album,
+                        profile,
+                        &settings,
+                        &upload_progress,
+                        bot.clone(),
+                        db.clone(),
+                    )
+                    .await
+                    .map(|count| sent_count += count);
+                album = next_album;
+                if sending_result.is_err() {
+                    downloader_cancellation_token_tx.cancel();
+                    break;
                 }
+                continue;
             }
-        }
-        let file_amount = paths.len();
-        trace!("{file_amount} {}(s) to send.", self.media_type());
-        // If count of files is 0 then it is an error even if yt-dlp doesn't think so.
-        // For example a file can be larger than 2GB thus not sendable by the bot.
-        if file_amount == 0 {
-            poller_cancellation_token_tx.cancel();
-            // Await poller handle before cleanup to avoid sending incorrect data to user.
-            poller_handle.await?;
-            cleanup(absolute_destination_path.into());
-            let error_text;
-            match ytdresult {
-                Ok(traceback) => {
-                    error_text = format!("{traceback:?}");
-                    return Err(error_text.into());
+            let file_path = file_info.filepath.clone();
+            if group_albums {
+                sending_result = self
+                    .prepare_file(file_info, profile, &settings)
+                    .await
+                    .map(|outgoing_files| playlist_album.extend(outgoing_files));
+                // A source that has nothing to send is not waiting for an album.
+                if !playlist_album
+                    .iter()
+                    .any(|outgoing_file| outgoing_file.info.filepath == file_path)
+                {
+                    remove_sent_file(&file_path);
+                }
+                while sending_result.is_ok() && playlist_album.len() >= ALBUM_MAX_ITEMS {
+                    sending_result = self
+                        .send_playlist_album(
+                            &mut playlist_album,
+                            profile,
+                            &settings,
+                            &upload_progress,
+                            bot.clone(),
+                            db.clone(),
+                        )
+                        .await
+                        .map(|count| sent_count += count);
                 }
-                Err(traceback) => {
-                    return Err(traceback);
+                if sending_result.is_err() {
+                    downloader_cancellation_token_tx.cancel();
+                    break;
+                }
+                continue;
+            }
+            sending_result = match self.prepare_file(file_info, profile, &settings).await {
+                Ok(outgoing_files) => {
+                    let mut result = Ok(());
+                    for outgoing_file in outgoing_files {
+                        // The number of files is known in advance only for playlists.
+                        let file_count = progress.lock().unwrap().playlist_count;
+                        *upload_progress.lock().unwrap() =
+                            Some(UploadProgress::new(sent_count + 1, file_count));
+                        result = self
+                            .send_file(
+                                &outgoing_file,
+                                profile,
+                                &settings,
+                                &upload_progress,
+                                bot.clone(),
+                                db.clone(),
+                            )
+                            .await;
+                        *upload_progress.lock().unwrap() = None;
+                        // The source is removed below, once its subtitles are sent.
+                        if outgoing_file.path != file_path {
+                            remove_sent_file(&outgoing_file.path);
+                        }
+                        if result.is_err() {
+                            break;
+                        }
+                        sent_count += 1;
+                    }
+                    if result.is_ok() && wants_subtitles(profile, SubtitleMode::Srt, &settings) {
+                        self.send_subtitles(&file_path, bot.clone()).await;
+                    }
+                    result
                 }
+                Err(e) => Err(e),
+            };
+            // Parts and renamed files share thumbnails with the original, thus it is removed last.
+            remove_sent_file(&file_path);
+            if sending_result.is_err() {
+                downloader_cancellation_token_tx.cancel();
+                break;
             }
         }
+        drop(finished_files_rx);
+        // The last album is sent once the download is complete.
+        if sending_result.is_ok() && !album.is_empty() {
+            sending_result = self
+                .send_album(
+                    &mut album,
+                    profile,
+                    &settings,
+                    &upload_progress,
+                    bot.clone(),
+                    db.clone(),
+                )
+                .await
+                .map(|count| sent_count += count);
+        }
+        while sending_result.is_ok() && !playlist_album.is_empty() {
+            sending_result = self
+                .send_playlist_album(
+                    &mut playlist_album,
+                    profile,
+                    &settings,
+                    &upload_progress,
+                    bot.clone(),
+                    db.clone(),
+                )
+                .await
+                .map(|count| sent_count += count);
+        }
+        let download_result = downloader_handle.await.unwrap();
+        self.set_proxy(proxy.lock().unwrap().take());
         // Stop poller task here.
         poller_cancellation_token_tx.cancel();
-        // Send files in alphabetic order.
-        for path in paths {
-            self.send_file(&path, bot.clone(), db.clone()).await?;
-        }
         // Await poller handle before cleanup to avoid sending incorrect data to user.
         poller_handle.await?;
-        cleanup(absolute_destination_path.into());
+        sending_result?;
+        trace!("{sent_count} file(s) of profile {} sent.", profile.id);
+        match download_result {
+            // Files that were sent don't make up for a download that was stopped or gave up halfway.
+            Err(e) => Err(DownloadError::from_error(e).into()),
+            // If count of files is 0 then it is an error even if the downloader doesn't think so.
+            // For example a file can be larger than 2GB thus not sendable by the bot.
+            Ok(diagnostics) if sent_count == 0 && diagnostics.trim().is_empty() => {
+                Err(i18n::text(&self.language, "nothing_to_send").into())
+            }
+            Ok(diagnostics) if sent_count == 0 => Err(DownloadError::classify(&diagnostics).into()),
+            Ok(_) => Ok(()),
+        }
+    }
+    // Subtitles are an addition to the video, failing to send them doesn't fail the task.
+    #[tracing::instrument(skip_all)]
+    async fn send_subtitles(&self, video_path: &Path, bot: LimitedBot) {
+        for subtitles in sidecar_files(video_path, "srt") {
+            if let Err(e) = bot
+                .send_document(self.chat_id(), InputFile::file(&subtitles))
+                .await
+            {
+                warn!("Failed to send subtitles '{}': {e}", subtitles.display());
+            }
+        }
+    }
+    // Sends images and videos of a post as an album with the post text as its caption, then deletes them.
+    // Files that can't be album items, such as GIFs and large images, follow as documents.
+    // Returns the number of files sent.
+    #[tracing::instrument(skip_all)]
+    async fn send_album(
+        &self,
+        album: &mut Vec<FileInfo>,
+        profile: &MediaProfile,
+        settings: &UserSettings,
+        upload_progress: &SharedUploadProgress,
+        bot: LimitedBot,
+        db: Surreal<DbClient>,
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let post_text = album.iter().find_map(|file_info| file_info.post_text());
+        let mut sendable = Vec::new();
+        let mut documents = Vec::new();
+        for file_info in album.iter() {
+            let path = &file_info.filepath;
+            if is_video_file(path)
+                || (is_image_file(path) && path.metadata()?.len() < PHOTO_MAX_SIZE)
+            {
+                sendable.push(file_info);
+            } else {
+                trace!(
+                    "File {} can't be sent as a photo, sending it as a document",
+                    path.display()
+                );
+                documents.push(file_info);
+            }
+        }
+        let sent_count = sendable.len() + documents.len();
+        // The post text goes to the first document if there is no album to caption.
+        let mut document_caption = post_text
+            .clone()
+            .filter(|_| sendable.is_empty())
+            .map(|text| text.chars().take(CAPTION_MAX_LENGTH).collect::<String>());
+        let mut result = match sendable.as_slice() {
+            [] => Ok(()),
+            // Telegram doesn't accept albums of a single item.
+            [file_info] => {
+                let outgoing_file = OutgoingFile {
+                    path: file_info.filepath.clone(),
+                    info: (*file_info).clone(),
+                    caption: post_text
+                        .map(|text| text.chars().take(CAPTION_MAX_LENGTH).collect::<String>()),
+                    thumbnail: None,
+                };
+                *upload_progress.lock().unwrap() = Some(UploadProgress::new(1, Some(1)));
+                let result = self
+                    .send_file(
+                        &outgoing_file,
+                        profile,
+                        settings,
+                        upload_progress,
+                        bot.clone(),
+                        db,
+                    )
+                    .await;
+                *upload_progress.lock().unwrap() = None;
+                result
+            }
+            _ => self.send_media_of_album(&sendable, post_text, &bot).await,
+        };
+        for file_info in &documents {
+            if result.is_err() {
+                break;
+            }
+            result = bot
+                .send_document(self.chat_id(), InputFile::file(&file_info.filepath))
+                .caption(document_caption.take().unwrap_or_default())
+                .await
+                .map(|_| ())
+                .map_err(|e| e.into());
+        }
+        for file_info in album.drain(..) {
+            remove_sent_file(&file_info.filepath);
+        }
+        result?;
+        Ok(sent_count)
+    }
+    // A single send_media_group call for the images and videos of a post.
+    async fn send_media_of_album(
+        &self,
+        sendable: &[&FileInfo],
+        mut caption: Option<String>,
+        bot: &LimitedBot,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut media = Vec::new();
+        for file_info in sendable {
+            let path = &file_info.filepath;
+            let file = InputFile::file(path);
+            // Telegram shows the caption of an album if only one of its items has it.
+            let item_caption = caption
+                .take()
+                .map(|text| text.chars().take(CAPTION_MAX_LENGTH).collect::<String>())
+                .unwrap_or_default();
+            if is_video_file(path) {
+                let video_metadata = get_video_metadata(path);
+                media.push(InputMedia::Video(
+                    InputMediaVideo::new(file)
+                        .caption(item_caption)
+                        .duration(to_u16(video_metadata.duration))
+                        .height(to_u16(video_metadata.height))
+                        .width(to_u16(video_metadata.width))
+                        .supports_streaming(true),
+                ));
+            } else {
+                media.push(InputMedia::Photo(
+                    InputMediaPhoto::new(file).caption(item_caption),
+                ));
+            }
+        }
+        let sent_count = media.len();
+        bot.send_media_group(self.chat_id(), media).await?;
+        info!("Album of {sent_count} items sent successfully.");
         Ok(())
     }
+    // Sends up to 10 audios or videos of a playlist as an album in their original order, then deletes them.
+    // If Telegram rejects the album, its files are sent one by one instead. Returns the number of files sent.
+    #[tracing::instrument(skip_all)]
+    async fn send_playlist_album(
+        &self,
+        playlist_album: &mut Vec<OutgoingFile>,
+        profile: &MediaProfile,
+        settings: &UserSettings,
+        upload_progress: &SharedUploadProgress,
+        bot: LimitedBot,
+        db: Surreal<DbClient>,
+    ) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let count = playlist_album.len().min(ALBUM_MAX_ITEMS);
+        let outgoing_files: Vec<OutgoingFile> = playlist_album.drain(..count).collect();
+        let result = match outgoing_files.as_slice() {
+            // Telegram doesn't accept albums of a single item.
+            [outgoing_file] => {
+                *upload_progress.lock().unwrap() = Some(UploadProgress::new(1, Some(1)));
+                self.send_file(
+                    outgoing_file,
+                    profile,
+                    settings,
+                    upload_progress,
+                    bot.clone(),
+                    db.clone(),
+                )
+                .await
+            }
+            _ => {
+                let album_result = self
+                    .send_media_of_playlist(
+                        &outgoing_files,
+                        profile,
+                        settings,
+                        upload_progress,
+                        &bot,
+                    )
+                    .await;
+                match album_result {
+                    Ok(()) => Ok(()),
+                    Err(e) => {
+                        warn!("Failed to send album, sending {count} file(s) one by one: {e}");
+                        let mut result = Ok(());
+                        for (index, outgoing_file) in outgoing_files.iter().enumerate() {
+                            *upload_progress.lock().unwrap() =
+                                Some(UploadProgress::new(index + 1, Some(count as u32)));
+                            result = self
+                                .send_file(
+                                    outgoing_file,
+                                    profile,
+                                    settings,
+                                    upload_progress,
+                                    bot.clone(),
+                                    db.clone(),
+                                )
+                                .await;
+                            if result.is_err() {
+                                break;
+                            }
+                        }
+                        result
+                    }
+                }
+            }
+        };
+        *upload_progress.lock().unwrap() = None;
+        // Sources are removed below, once their subtitles are sent.
+        for outgoing_file in &outgoing_files {
+            if outgoing_file.path != outgoing_file.info.filepath {
+                remove_sent_file(&outgoing_file.path);
+            }
+        }
+        // Parts of a file may end up in different albums, the source is removed with its last part.
+        let mut sources: Vec<&PathBuf> = outgoing_files
+            .iter()
+            .map(|outgoing_file| &outgoing_file.info.filepath)
+            .collect();
+        sources.dedup();
+        for source in sources {
+            if playlist_album
+                .iter()
+                .any(|outgoing_file| &outgoing_file.info.filepath == source)
+            {
+                continue;
+            }
+            if result.is_ok() && wants_subtitles(profile, SubtitleMode::Srt, settings) {
+                self.send_subtitles(source, bot.clone()).await;
+            }
+            remove_sent_file(source);
+        }
+        result?;
+        Ok(count)
+    }
+    // A single send_media_group call for the files of a playlist album.
+    async fn send_media_of_playlist(
+        &self,
+        outgoing_files: &[OutgoingFile],
+        profile: &MediaProfile,
+        settings: &UserSettings,
+        upload_progress: &SharedUploadProgress,
+        bot: &LimitedBot,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let tag_audio = settings.tag_audio && profile.send == SendMethod::Audio;
+        let parse_mode = caption::parse_mode(settings.caption_format);
+        // The whole album is a single upload, so its progress is counted over all of its files.
+        let mut upload = UploadProgress::new(1, Some(outgoing_files.len() as u32));
+        for outgoing_file in outgoing_files {
+            upload.total_bytes += outgoing_file.path.metadata()?.len();
+        }
+        *upload_progress.lock().unwrap() = Some(upload);
+        let mut media = Vec::new();
+        for outgoing_file in outgoing_files {
+            let path = &outgoing_file.path;
+            let file_name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let reader =
+                ProgressReader::new(tokio::fs::File::open(path).await?, upload_progress.clone());
+            let file = InputFile::read(reader).file_name(file_name);
+            let info = &outgoing_file.info;
+            // Albums show the caption of every item, so each one is at least captioned with its own title.
+            let mut caption = caption_of(outgoing_file, profile, settings);
+            if settings.caption_template.is_empty()
+                && let Some(title) = info.track_title()
+            {
+                let title = caption::escape(&title, settings.caption_format);
+                caption = [title, caption].join("\n").trim().to_string();
+            }
+            let wants_thumbnail = tag_audio || profile.send == SendMethod::Video;
+            let thumbnail_file = match outgoing_file.thumbnail.clone() {
+                Some(thumbnail_path) if wants_thumbnail => load_thumbnail(thumbnail_path).await?,
+                _ => None,
+            };
+            let video_metadata = get_video_metadata(path);
+            match profile.send {
+                SendMethod::Audio => {
+                    let mut item = InputMediaAudio::new(file)
+                        .caption(caption)
+                        .parse_mode(parse_mode);
+                    if tag_audio {
+                        item = item.duration(to_u16(video_metadata.duration));
+                        if let Some(title) = info.track_title() {
+                            item = item.title(title);
+                        }
+                        if let Some(performer) = info.performer() {
+                            item = item.performer(performer);
+                        }
+                    }
+                    item.thumbnail = thumbnail_file;
+                    media.push(InputMedia::Audio(item));
+                }
+                _ => {
+                    let mut item = InputMediaVideo::new(file)
+                        .caption(caption)
+                        .parse_mode(parse_mode)
+                        .duration(to_u16(video_metadata.duration))
+                        .height(to_u16(video_metadata.height))
+                        .width(to_u16(video_metadata.width))
+                        .supports_streaming(true);
+                    item.thumbnail = thumbnail_file;
+                    media.push(InputMedia::Video(item));
+                }
+            }
+        }
+        let media_count = media.len();
+        let action = chat_action(profile.send, &outgoing_files[0].path);
+        let request = bot.send_media_group(self.chat_id(), media).into_future();
+        with_chat_action(bot, self.chat_id(), action, request).await?;
+        info!("Album of {media_count} items sent successfully.");
+        Ok(())
+    }
+    // Turns a finished download into the files to send. Empty if it can't or shouldn't be sent.
+    async fn prepare_file(
+        &self,
+        info: FileInfo,
+        profile: &MediaProfile,
+        settings: &UserSettings,
+    ) -> Result<Vec<OutgoingFile>, Box<dyn Error + Send + Sync>> {
+        let file_path = info.filepath.clone();
+        let filename = file_path.display().to_string();
+        if !profile.matches_extension(&file_path) {
+            trace!("Skipping unexpected file {filename}");
+            return Ok(vec![]);
+        }
+        // The backend places .jpg thumbnail next to the file in the same folder with the same base name.
+        let thumbnail =
+            Some(file_path.with_extension("jpg")).filter(|thumbnail| thumbnail.exists());
+        // Conversions produce a new file from whatever was downloaded. The source is removed by the caller.
+        let file_path = match profile.postprocess {
+            PostProcess::Animation => {
+                debug!("Converting {filename} to animation ...");
+                to_animation(&file_path, ANIMATION_MAX_DURATION, ANIMATION_MAX_SIZE)
+                    .await
+                    .map_err(conversion_error)?
+            }
+            // A time range given after the URL picks the moment to turn into a sticker.
+            PostProcess::Sticker => {
+                debug!("Converting {filename} to sticker ...");
+                to_sticker(&file_path).await.map_err(conversion_error)?
+            }
+            // A time range given after the URL sets where the note starts.
+            PostProcess::VideoNote => {
+                debug!("Converting {filename} to video note ...");
+                to_video_note(&file_path, VIDEO_NOTE_LENGTH, VIDEO_NOTE_MAX_DURATION)
+                    .await
+                    .map_err(conversion_error)?
+            }
+            PostProcess::None => file_path,
+        };
+        // Burning is done before the size check, as re-encoding changes the size.
+        if wants_subtitles(profile, SubtitleMode::Burn, settings) {
+            match preferred_subtitles(&file_path, &settings.subtitle_languages) {
+                Some(subtitles) => {
+                    debug!("Burning subtitles {} ...", subtitles.display());
+                    burn_subtitles(&file_path, &subtitles)
+                        .await
+                        .map_err(conversion_error)?;
+                }
+                None => debug!("No subtitles to burn into {filename}"),
+            }
+        }
+        // Local Telegram API allows bots sending only files under 2 GB, users may prefer even less.
+        let size_limit = settings.size_limit_bytes();
+        let filesize = file_path.metadata()?.len();
+        if filesize >= size_limit {
+            let resizable = matches!(profile.send, SendMethod::Audio | SendMethod::Video);
+            // Originals are cut without re-encoding, but never re-encoded to fit.
+            let splittable = resizable || profile.send == SendMethod::Document;
+            match settings.oversize_policy {
+                OversizePolicy::Split if splittable => {
+                    debug!("Splitting large file {filename} ...");
+                    let parts = split_into_parts(&file_path, size_limit)
+                        .await
+                        .map_err(too_large_error)?;
+                    let parts_count = parts.len();
+                    let outgoing_files = parts
+                        .into_iter()
+                        .enumerate()
+                        .map(|(index, path)| OutgoingFile {
+                            path,
+                            info: info.clone(),
+                            caption: Some(i18n::text_with(
+                                &self.language,
+                                "part",
+                                &[("number", &(index + 1)), ("count", &parts_count)],
+                            )),
+                            thumbnail: thumbnail.clone(),
+                        })
+                        .collect();
+                    return Ok(outgoing_files);
+                }
+                OversizePolicy::Fit if resizable => {
+                    debug!("Re-encoding large file {filename} to fit ...");
+                    fit_to_size(&file_path, size_limit)
+                        .await
+                        .map_err(too_large_error)?;
+                }
+                _ => {
+                    trace!("Skipping large file {filename}");
+                    return Ok(vec![]);
+                }
+            }
+        }
+        // Rename .opus into .ogg because Telegram requires so to display wave pattern.
+        if let SendMethod::Voice = profile.send {
+            let timestamp = timestamp(SystemTime::now())
+                .to_string()
+                .replace(":", "-")
+                .replace("T", "_")
+                .replace("Z", "");
+            // Filename formatting that is used by Telegram when sending voice messages.
+            let new_path = file_path.with_file_name(format!("audio_{timestamp}.ogg"));
+            std::fs::rename(&file_path, &new_path)?;
+            return Ok(vec![OutgoingFile {
+                path: new_path,
+                info,
+                caption: None,
+                thumbnail: None,
+            }]);
+        }
+        Ok(vec![OutgoingFile {
+            path: file_path,
+            info,
+            caption: None,
+            thumbnail,
+        }])
+    }
 }
 
-use crate::FILE_STORAGE;
-pub fn construct_destination_path(task_id: String) -> String {
-    format!("{FILE_STORAGE}/{task_id}")
+// Subtitles are downloaded only for videos.
+fn wants_subtitles(profile: &MediaProfile, mode: SubtitleMode, settings: &UserSettings) -> bool {
+    profile.send == SendMethod::Video && settings.subtitle_mode == mode
 }
 
-use std::path::Path;
+// A file ready to be sent to the chat.
+struct OutgoingFile {
+    path: PathBuf,
+    info: FileInfo,
+    caption: Option<String>,
+    thumbnail: Option<PathBuf>,
+}
 
-fn generate_yt_dlp_args(media_type: MediaType, url: Url) -> Vec<String> {
-    // Check if cookies file exists
-    let cookies_path = Path::new("/app/cookies/cookies.txt");
-    let has_cookies = cookies_path.exists();
-    // Common arguments every branch should have
-    let mut args = vec![
-        String::from("--concurrent-fragments"),
-        String::from("1"),
-        String::from("--skip-playlist-after-errors"),
-        String::from("5000"),
-        String::from("--max-filesize"),
-        String::from("2000M"),
-        String::from("--windows-filenames"),
-        String::from("--no-write-info-json"),
-        String::from("--no-embed-metadata"),
-        String::from("--write-thumbnail"),
-        String::from("--convert-thumbnails"),
-        String::from("jpg"),
-        String::from("--sponsorblock-remove"),
-        String::from("sponsor"),
-    ];
-
-    // Media-specific arguments
-    match media_type {
-        MediaType::Mp3 => args.extend(vec![
-            String::from("--extract-audio"),
-            String::from("--output"),
-            String::from("%(title)s.mp3"),
-            String::from("--audio-format"),
-            String::from("mp3"),
-            String::from("--audio-quality"),
-            String::from("0"),
-            String::from(url),
-        ]),
-        MediaType::Mp4 => args.extend(vec![
-            String::from("--output"),
-            String::from("%(title)s.mp4"),
-            String::from("--format"),
-            String::from("bestvideo+bestaudio/best"),
-            String::from("--merge-output-format"),
-            String::from("mp4"),
-            String::from("--recode-video"),
-            String::from("mp4"),
-            String::from(url),
-        ]),
-        MediaType::Voice => args.extend(vec![
-            String::from("--extract-audio"),
-            String::from("--audio-format"),
-            String::from("opus"),
-            String::from("--audio-quality"),
-            String::from("64K"),
-            String::from(url),
-        ]),
-    };
+// Animations are meant as short reactions, longer clips are cut and big ones are re-encoded to fit.
+const ANIMATION_MAX_DURATION: u32 = 60;
+const ANIMATION_MAX_SIZE: u64 = 20_000_000;
 
-    // Insert cookies arguments at the beginning if cookies file exists
-    if has_cookies {
-        debug!("Using the provided cookies.txt file ...");
-        args.insert(0, String::from("--cookies"));
-        args.insert(1, String::from("/app/cookies/cookies.txt"));
-    }
+// Diameter in pixels and duration in seconds that Telegram accepts for video notes.
+const VIDEO_NOTE_LENGTH: u32 = 384;
+const VIDEO_NOTE_MAX_DURATION: u32 = 60;
 
-    args
+// Telegram limits for albums: number of items and size of a photo in bytes.
+const ALBUM_MAX_ITEMS: usize = 10;
+const PHOTO_MAX_SIZE: u64 = 10_000_000;
+
+// Telegram shows a chat action for 5 seconds, it is renewed a bit earlier.
+const CHAT_ACTION_INTERVAL_SECS: u64 = 4;
+
+// Amount of finished files that may wait for sending before yt-dlp is paused.
+const FINISHED_FILES_BUFFER: usize = 2;
+
+// Caption from the user's template, followed by the part number of split files.
+// Files of posts have no metadata to fill the template with, they are captioned with the post text instead.
+fn caption_of(
+    outgoing_file: &OutgoingFile,
+    profile: &MediaProfile,
+    settings: &UserSettings,
+) -> String {
+    let format = settings.caption_format;
+    let mut lines = Vec::new();
+    if !settings.caption_template.is_empty() && profile.send != SendMethod::Album {
+        lines.push(caption::render(
+            &settings.caption_template,
+            format,
+            &outgoing_file.info,
+        ));
+    }
+    if let Some(text) = &outgoing_file.caption {
+        lines.push(caption::escape(text, format));
+    }
+    lines.join("\n").trim().to_string()
 }
 
-#[tracing::instrument(skip_all)]
-async fn yt_dlp(
-    path: PathBuf,
-    args: Vec<String>,
-    cancellation_token: CancellationToken,
-) -> Result<std::process::Output, Box<dyn Error + Send + Sync>> {
-    debug!("Downloading ...");
-    let mut cmd = Command::new("yt-dlp");
-    std::fs::create_dir_all(&path)?;
-    cmd.current_dir(&path)
-        .env("LC_ALL", "en_US.UTF-8")
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped());
-
-    // Add all arguments
-    for arg in args {
-        cmd.arg(arg);
+// Button that opens the page the file was downloaded from, if the user wants it and the page is known.
+fn source_button(
+    info: &FileInfo,
+    settings: &UserSettings,
+    language: &str,
+) -> Option<InlineKeyboardMarkup> {
+    if !settings.source_button {
+        return None;
     }
+    let url = Url::parse(info.webpage_url.as_deref()?).ok()?;
+    let button = InlineKeyboardButton::url(i18n::text(language, "source_button"), url);
+    Some(InlineKeyboardMarkup::new([[button]]))
+}
 
-    // Spawn the child process
-    let mut child = cmd.spawn()?;
+// Compresses the thumbnail, a missing or broken thumbnail is not a reason to fail the upload.
+async fn load_thumbnail(
+    mut thumbnail_path: PathBuf,
+) -> Result<Option<InputFile>, tokio::task::JoinError> {
+    tokio::task::spawn_blocking(move || {
+        compress_thumbnail(&mut thumbnail_path)
+            .map(|_| InputFile::file(thumbnail_path))
+            .inspect_err(|e| warn!("Thumbnail is not usable: {e}"))
+            .ok()
+    })
+    .await
+}
 
-    // Get handles to stdout and stderr
-    let stdout = child.stdout.take().expect("Failed to capture stdout");
-    let stderr = child.stderr.take().expect("Failed to capture stderr");
+// Album items take smaller integers than single videos do.
+fn to_u16(value: u32) -> u16 {
+    u16::try_from(value).unwrap_or(u16::MAX)
+}
 
-    // Create readers for the streams
-    let mut stdout_reader = BufReader::new(stdout).lines();
-    let mut stderr_reader = BufReader::new(stderr).lines();
+// Opens the file so that the bytes read by the upload are counted in the upload progress.
+async fn open_for_upload(
+    path: &Path,
+    file_name: &str,
+    upload_progress: &SharedUploadProgress,
+) -> Result<InputFile, std::io::Error> {
+    let file = tokio::fs::File::open(path).await?;
+    let total_bytes = file.metadata().await?.len();
+    if let Some(upload) = upload_progress.lock().unwrap().as_mut() {
+        upload.sent_bytes = 0;
+        upload.total_bytes = total_bytes;
+    }
+    let reader = ProgressReader::new(file, upload_progress.clone());
+    Ok(InputFile::read(reader).file_name(file_name.to_string()))
+}
 
-    let current_span_1 = tracing::Span::current();
-    let current_span_2 = tracing::Span::current();
+fn chat_action(send: SendMethod, path: &Path) -> ChatAction {
+    match send {
+        SendMethod::Video | SendMethod::Animation => ChatAction::UploadVideo,
+        SendMethod::Audio | SendMethod::Voice => ChatAction::UploadVoice,
+        SendMethod::VideoNote => ChatAction::UploadVideoNote,
+        SendMethod::Album if is_video_file(path) => ChatAction::UploadVideo,
+        SendMethod::Album => ChatAction::UploadPhoto,
+        SendMethod::Document | SendMethod::Sticker => ChatAction::UploadDocument,
+    }
+}
 
-    // Spawn tasks to process stdout and stderr
-    let stdout_task = tokio::spawn(async move {
-        while let Ok(Some(line)) = stdout_reader.next_line().await {
-            tracing::trace!(parent: current_span_1.clone(), "stdout: {}", line);
+// Keeps the chat action visible while the request runs, Telegram clears it after 5 seconds.
+async fn with_chat_action<F: Future>(
+    bot: &LimitedBot,
+    chat_id: ChatId,
+    action: ChatAction,
+    request: F,
+) -> F::Output {
+    tokio::pin!(request);
+    let mut interval = tokio::time::interval(Duration::from_secs(CHAT_ACTION_INTERVAL_SECS));
+    loop {
+        tokio::select! {
+            output = &mut request => return output,
+            _ = interval.tick() => {
+                // Chat actions are cosmetic, they are the first thing to skip under pressure.
+                if let Some(request) = bot.try_send_chat_action(chat_id, action)
+                    && let Err(e) = request.await
+                {
+                    trace!("Failed to send chat action: {e}");
+                }
+            }
         }
-    });
+    }
+}
 
-    let stderr_task = tokio::spawn(async move {
-        let mut error_traceback = String::new();
-        while let Ok(Some(line)) = stderr_reader.next_line().await {
-            error_traceback += &line;
-            tracing::warn!(parent: current_span_2.clone(), "stderr: {}", line);
-        }
-        error_traceback
-    });
-
-    // Use select! to wait for either completion or cancellation
-    let result = tokio::select! {
-        biased;
-        // Handle cancellation
-        _ = cancellation_token.cancelled() => {
-            if let Some(pid) = child.id() {
-                // Spawn the `kill` command with the PID as argument
-                let output = std::process::Command::new("kill")
-                    .arg(pid.to_string())
-                    .output();
-            
-                match output {
-                    Ok(o) if o.status.success() => {}
-                    Ok(o) => {
-                        warn!(
-                            "Failed to send SIGTERM to child process (pid {}): kill exited with {:?}",
-                            pid, o.status.code()
-                        );
-                    }
-                    Err(e) => {
-                        warn!("Failed to execute kill command: {}", e);
-                    }
-                }
+// Failures of ffmpeg are logged and shown to admins as they are, users get a message in their language.
+fn conversion_error(log: String) -> DownloadError {
+    DownloadError::with_log(DownloadErrorKind::Conversion, log)
+}
+
+// Splitting and fitting fail mostly when the file can't get under the limit.
+fn too_large_error(log: String) -> DownloadError {
+    DownloadError::with_log(DownloadErrorKind::TooLarge, log)
+}
 
-                // Wait a moment for graceful shutdown
-                tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+// Admins get the end of the downloader log with failures, that's where the error is.
+const ADMIN_LOG_MAX_LENGTH: usize = 3000;
 
-                // If process is still running, force kill with SIGKILL
-                if let Err(e) = child.kill().await {
-                    warn!("Failed to kill child process: {}", e);
-                }
-            } else {
-                // Fallback to regular kill if no PID
-                if let Err(e) = child.kill().await {
-                    warn!("Failed to kill child process: {}", e);
-                }
-            }
+// Last lines of the log that fit into a message.
+fn log_tail(log: &str) -> String {
+    let log = log.trim();
+    let mut start = log.len().saturating_sub(ADMIN_LOG_MAX_LENGTH);
+    while !log.is_char_boundary(start) {
+        start += 1;
+    }
+    log[start..].to_string()
+}
 
-            // Wait for the process to exit
-            let _ = child.wait_with_output().await;
+// Deletes a sent file together with its thumbnails and subtitles, so that disk usage doesn't grow with playlist length.
+fn remove_sent_file(path: &Path) {
+    let sidecars = [sidecar_files(path, "srt"), sidecar_files(path, "vtt")].concat();
+    for leftover in [
+        path.to_path_buf(),
+        path.with_extension("jpg"),
+        path.with_extension("jpeg"),
+        metadata_path(path),
+    ]
+    .into_iter()
+    .chain(sidecars)
+    {
+        let _ = std::fs::remove_file(leftover);
+    }
+}
 
-            // Cancel the stream processing tasks
-            stdout_task.abort();
-            stderr_task.abort();
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extensions.contains(&extension.to_lowercase().as_str()))
+}
 
-            cleanup(path);
-            Err(Box::<dyn Error + Send + Sync>::from("Operation cancelled."))
-        }
-        // Timeout for a task is 3h (10800 seconds)
-        _ = tokio::time::sleep(std::time::Duration::from_secs(10800)) => {
-            // Handle timeout after 5 minutes
-            warn!("Process timed out after 5 minutes");
-            if let Err(e) = child.kill().await {
-                warn!("Failed to kill timed out process: {}", e);
-            }
-            let _ = child.wait_with_output().await;
-            cleanup(path);
-            Err("Operation timeout (3 hours).".into())
-        }
-        // Wait for the process to complete normally
-        status = child.wait() => {
-            //
-            match status {
-                Ok(_) => {
-                    // Wait for stream processing to complete
-                    let (_, stderr) = tokio::join!(stdout_task, stderr_task);
-                    let stderr_bytes = stderr.unwrap().into_bytes();
-                    let mut output = child.wait_with_output().await?;
-                    output.stderr = stderr_bytes;
-                    Ok(output)
-                }
-                Err(e) => {
-                    // Cancel the stream processing tasks on error
-                    stdout_task.abort();
-                    stderr_task.abort();
-                    Err(Box::<dyn Error + Send + Sync>::from(e))
-                }
-            }
-        }
+fn is_video_file(path: &Path) -> bool {
+    has_extension(path, &["mp4", "m4v", "mov", "webm"])
+}
+
+fn is_image_file(path: &Path) -> bool {
+    has_extension(path, &["jpg", "jpeg", "png", "webp"])
+}
+
+// Files yt-dlp places next to a media file, named like <base name>.<language>.<extension>.
+fn sidecar_files(path: &Path, extension: &str) -> Vec<PathBuf> {
+    let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+        return vec![];
     };
+    let prefix = format!("{stem}.");
+    let suffix = format!(".{extension}");
+    let mut sidecars: Vec<PathBuf> = path
+        .parent()
+        .and_then(|parent| std::fs::read_dir(parent).ok())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|sidecar| {
+            sidecar
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(&prefix) && name.ends_with(&suffix))
+        })
+        .collect();
+    sidecars.sort();
+    sidecars
+}
+
+// Picks subtitles in the first preferred language that has any. Patterns such as en.* match by prefix.
+fn preferred_subtitles(video_path: &Path, languages: &str) -> Option<PathBuf> {
+    let subtitles = sidecar_files(video_path, "srt");
+    let stem = video_path.file_stem()?.to_str()?;
+    languages.split(',').find_map(|language| {
+        let language = language.trim_end_matches(".*");
+        subtitles
+            .iter()
+            .find(|subtitles| {
+                subtitles
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .and_then(|name| name.strip_prefix(stem))
+                    .is_some_and(|rest| rest.starts_with(&format!(".{language}")))
+            })
+            .cloned()
+    })
+}
 
-    Ok(result?)
+use crate::FILE_STORAGE;
+pub fn construct_destination_path(task_id: String) -> String {
+    format!("{FILE_STORAGE}/{task_id}")
 }
+
+use std::path::Path;
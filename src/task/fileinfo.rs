@@ -0,0 +1,104 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+// Marker that prefixes the file info printed by yt-dlp once a file is completely processed.
+const FILE_INFO_PREFIX: &str = "[telepirate-file]";
+
+// Metadata of a finished file, as printed by yt-dlp.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct FileInfo {
+    pub filepath: PathBuf,
+    pub title: Option<String>,
+    pub track: Option<String>,
+    pub artist: Option<String>,
+    pub uploader: Option<String>,
+    // Seconds, yt-dlp gives a fraction for some sites.
+    pub duration: Option<f64>,
+    // YYYYMMDD.
+    pub upload_date: Option<String>,
+    pub webpage_url: Option<String>,
+    pub playlist_index: Option<u32>,
+}
+
+// Keys gallery-dl uses for the text of a post, depending on the site.
+const POST_TEXT_KEYS: [&str; 4] = ["description", "content", "caption", "title"];
+// Keys gallery-dl uses for the ID of a post, the generic one last since some sites use it for single files.
+const POST_ID_KEYS: [&str; 3] = ["tweet_id", "post_id", "id"];
+
+impl FileInfo {
+    // Passed to yt-dlp as --print. The after_move stage fires once a file is fully post-processed and moved,
+    // and the .{...}j conversion dumps the selected fields as a single line of JSON.
+    pub fn template() -> String {
+        format!(
+            "after_move:{FILE_INFO_PREFIX}%(.{{filepath,title,track,artist,uploader,duration,upload_date,webpage_url,playlist_index}})j"
+        )
+    }
+
+    // Returns None if the line is not a file info line.
+    pub fn parse_line(line: &str) -> Option<Self> {
+        let json = line.trim().strip_prefix(FILE_INFO_PREFIX)?;
+        serde_json::from_str(json)
+            .inspect_err(|e| warn!("Unparsable file info '{json}': {e}"))
+            .ok()
+    }
+
+    // gallery-dl prints the absolute path of every downloaded file, anything else is not a file info line.
+    pub fn parse_downloaded_path(line: &str, directory: &Path) -> Option<Self> {
+        let filepath = PathBuf::from(line.trim());
+        if !filepath.starts_with(directory) || !filepath.is_file() {
+            return None;
+        }
+        Some(Self {
+            filepath,
+            ..Default::default()
+        })
+    }
+
+    // Metadata file written by gallery-dl with --write-metadata.
+    fn metadata(&self) -> Option<serde_json::Value> {
+        std::fs::read(metadata_path(&self.filepath))
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+    }
+
+    // Text of the post a gallery-dl file belongs to.
+    pub fn post_text(&self) -> Option<String> {
+        let metadata = self.metadata()?;
+        POST_TEXT_KEYS.iter().find_map(|key| {
+            metadata
+                .get(key)
+                .and_then(|value| value.as_str())
+                .map(str::trim)
+                .filter(|text| !text.is_empty())
+                .map(String::from)
+        })
+    }
+
+    // Tells apart the posts of a gallery, e.g. of a profile. Files without an ID are told apart by the post text.
+    pub fn post_key(&self) -> Option<String> {
+        let id = self.metadata().and_then(|metadata| {
+            POST_ID_KEYS
+                .iter()
+                .find_map(|key| metadata.get(key).map(|value| format!("{key}={value}")))
+        });
+        id.or_else(|| self.post_text())
+    }
+
+    // Track title for music players, falls back to the video title.
+    pub fn track_title(&self) -> Option<String> {
+        self.track.clone().or_else(|| self.title.clone())
+    }
+
+    // Performer for music players, falls back to the channel name.
+    pub fn performer(&self) -> Option<String> {
+        self.artist.clone().or_else(|| self.uploader.clone())
+    }
+}
+
+// gallery-dl names metadata files after the full file name, e.g. photo.jpg.json.
+pub fn metadata_path(filepath: &Path) -> PathBuf {
+    let mut metadata_path = filepath.as_os_str().to_owned();
+    metadata_path.push(".json");
+    PathBuf::from(metadata_path)
+}
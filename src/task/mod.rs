@@ -1,8 +1,12 @@
 pub mod cancellation;
+pub mod caption;
 pub mod download;
+pub mod fileinfo;
 pub mod id;
-pub mod mediatype;
+pub mod progress;
+pub mod retry;
 pub mod simple;
 pub mod state;
 pub mod stats;
+pub mod timerange;
 pub mod traits;
@@ -0,0 +1,237 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::i18n;
+
+// Marker that prefixes yt-dlp progress lines, so they can be told apart from the rest of stdout.
+const PROGRESS_PREFIX: &str = "[telepirate-progress]";
+const PROGRESS_BAR_WIDTH: usize = 10;
+
+// Progress state shared between the downloader and the message updater.
+pub type SharedProgress = Arc<Mutex<DownloadProgress>>;
+// Upload state shared between the sender and the message updater, None while nothing is being uploaded.
+pub type SharedUploadProgress = Arc<Mutex<Option<UploadProgress>>>;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DownloadProgress {
+    pub percent: Option<f64>,
+    // Bytes per second
+    pub speed: Option<f64>,
+    pub eta: Option<Duration>,
+    pub playlist_index: Option<u32>,
+    pub playlist_count: Option<u32>,
+}
+
+impl DownloadProgress {
+    // Passed to yt-dlp as --progress-template. Raw numeric fields are used instead of the
+    // preformatted _percent_str and friends, because those contain ANSI colors and padding.
+    // Missing fields are rendered by yt-dlp as NA.
+    pub fn template() -> String {
+        format!(
+            "download:{PROGRESS_PREFIX}\
+            %(progress.downloaded_bytes)s|\
+            %(progress.total_bytes,progress.total_bytes_estimate)s|\
+            %(progress.speed)s|\
+            %(progress.eta)s|\
+            %(info.playlist_index)s|\
+            %(info.n_entries,info.playlist_count)s"
+        )
+    }
+
+    // Returns None if the line is not a progress line.
+    pub fn parse_line(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line
+            .trim()
+            .strip_prefix(PROGRESS_PREFIX)?
+            .split('|')
+            .collect();
+        if fields.len() != 6 {
+            return None;
+        }
+        let number = |field: &str| field.trim().parse::<f64>().ok();
+        let percent = match (number(fields[0]), number(fields[1])) {
+            (Some(downloaded), Some(total)) if total > 0.0 => {
+                Some((downloaded / total * 100.0).clamp(0.0, 100.0))
+            }
+            _ => None,
+        };
+        Some(Self {
+            percent,
+            speed: number(fields[2]),
+            eta: number(fields[3]).map(|eta| Duration::from_secs(eta as u64)),
+            playlist_index: fields[4].trim().parse().ok(),
+            playlist_count: fields[5].trim().parse().ok(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    // Renders multiline status text, skipping the parts yt-dlp didn't report.
+    pub fn render(&self, language: &str) -> String {
+        let mut lines = Vec::new();
+        match (self.playlist_index, self.playlist_count) {
+            (Some(index), Some(count)) => lines.push(i18n::text_with(
+                language,
+                "progress_item_of",
+                &[("index", &index), ("count", &count)],
+            )),
+            (Some(index), None) => lines.push(i18n::text_with(
+                language,
+                "progress_item",
+                &[("index", &index)],
+            )),
+            _ => {}
+        }
+        if let Some(percent) = self.percent {
+            let filled = ((percent / 100.0) * PROGRESS_BAR_WIDTH as f64).round() as usize;
+            let bar = "█".repeat(filled) + &"░".repeat(PROGRESS_BAR_WIDTH - filled);
+            lines.push(format!("{bar} {percent:.1}%"));
+        }
+        let mut details = Vec::new();
+        if let Some(speed) = self.speed {
+            let speed = format!("{:.2}", speed / (1024.0 * 1024.0));
+            details.push(i18n::text_with(
+                language,
+                "progress_speed",
+                &[("speed", &speed)],
+            ));
+        }
+        if let Some(eta) = self.eta {
+            details.push(i18n::text_with(
+                language,
+                "progress_eta",
+                &[("eta", &humantime::format_duration(eta))],
+            ));
+        }
+        if !details.is_empty() {
+            lines.push(details.join(" "));
+        }
+        lines.join("\n")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UploadProgress {
+    // Position of the file among the files of the task, starting from 1.
+    pub file_number: usize,
+    // Known for playlists only.
+    pub file_count: Option<u32>,
+    pub sent_bytes: u64,
+    pub total_bytes: u64,
+}
+
+impl UploadProgress {
+    pub fn new(file_number: usize, file_count: Option<u32>) -> Self {
+        Self {
+            file_number,
+            file_count,
+            sent_bytes: 0,
+            total_bytes: 0,
+        }
+    }
+
+    pub fn render(&self, language: &str) -> String {
+        let percent = (self.sent_bytes as f64 / self.total_bytes.max(1) as f64 * 100.0).min(100.0);
+        let percent = format!("{percent:.0}");
+        match self.file_count {
+            Some(count) => i18n::text_with(
+                language,
+                "uploading_file_of",
+                &[
+                    ("number", &self.file_number),
+                    ("count", &count),
+                    ("percent", &percent),
+                ],
+            ),
+            None => i18n::text_with(
+                language,
+                "uploading_file",
+                &[("number", &self.file_number), ("percent", &percent)],
+            ),
+        }
+    }
+}
+
+// Counts the bytes of a file as they are read for the upload.
+pub struct ProgressReader<R> {
+    inner: R,
+    progress: SharedUploadProgress,
+}
+
+impl<R> ProgressReader<R> {
+    pub fn new(inner: R, progress: SharedUploadProgress) -> Self {
+        Self { inner, progress }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled_before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll
+            && let Some(upload) = self.progress.lock().unwrap().as_mut()
+        {
+            upload.sent_bytes += (buf.filled().len() - filled_before) as u64;
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_progress_lines() {
+        let progress =
+            DownloadProgress::parse_line("[telepirate-progress]512|2048|1048576.5|42.7|3|10\n")
+                .unwrap();
+        assert_eq!(
+            progress,
+            DownloadProgress {
+                percent: Some(25.0),
+                speed: Some(1048576.5),
+                eta: Some(Duration::from_secs(42)),
+                playlist_index: Some(3),
+                playlist_count: Some(10),
+            }
+        );
+    }
+
+    #[test]
+    fn missing_fields_are_none() {
+        let progress =
+            DownloadProgress::parse_line("[telepirate-progress]512|NA|NA|NA|NA|NA").unwrap();
+        assert!(progress.is_empty());
+    }
+
+    #[test]
+    fn percent_is_clamped() {
+        let progress =
+            DownloadProgress::parse_line("[telepirate-progress]4096|2048|NA|NA|NA|NA").unwrap();
+        assert_eq!(progress.percent, Some(100.0));
+    }
+
+    #[test]
+    fn ignores_other_lines() {
+        assert_eq!(
+            DownloadProgress::parse_line("[download] Destination: video.mp4"),
+            None
+        );
+        assert_eq!(
+            DownloadProgress::parse_line("[telepirate-progress]512|2048"),
+            None
+        );
+    }
+}
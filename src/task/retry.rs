@@ -0,0 +1,48 @@
+use std::time::Duration;
+
+use teloxide::{ApiError, RequestError};
+
+// Uploads of big files fail now and then, a few attempts with growing pauses get most of them through.
+pub const SEND_ATTEMPTS: u32 = 6;
+const BASE_DELAY_SECS: u64 = 5;
+const MAX_DELAY_SECS: u64 = 300;
+
+// Fragments of Telegram errors that teloxide doesn't have a variant for, and that won't go away by retrying.
+const PERMANENT_ERROR_FRAGMENTS: [&str; 4] = [
+    "too big",
+    "too large",
+    "wrong file identifier",
+    "have no rights",
+];
+
+// How long to wait before sending again, None if retrying can't help.
+pub fn retry_delay(error: &RequestError, attempt: u32) -> Option<Duration> {
+    match error {
+        // Flood control says exactly how long to wait.
+        RequestError::RetryAfter(seconds) => Some(seconds.duration()),
+        RequestError::Network(_) | RequestError::Io(_) | RequestError::InvalidJson { .. } => {
+            Some(backoff(attempt))
+        }
+        // Telegram's own hiccups come as unknown errors such as "Internal Server Error".
+        RequestError::Api(ApiError::Unknown(text)) => {
+            let text = text.to_lowercase();
+            let is_permanent = PERMANENT_ERROR_FRAGMENTS
+                .iter()
+                .any(|fragment| text.contains(fragment));
+            (!is_permanent).then(|| backoff(attempt))
+        }
+        // The known API errors are about the request or the chat, e.g. the bot was blocked.
+        RequestError::Api(_) | RequestError::MigrateToChatId(_) => None,
+    }
+}
+
+// A caption template with broken markup, which fails the same way on every attempt.
+pub fn is_markup_error(error: &RequestError) -> bool {
+    matches!(error, RequestError::Api(ApiError::CantParseEntities(_)))
+}
+
+// 5, 10, 20 ... seconds, up to 5 minutes.
+fn backoff(attempt: u32) -> Duration {
+    let delay = BASE_DELAY_SECS.saturating_mul(2u64.saturating_pow(attempt.saturating_sub(1)));
+    Duration::from_secs(delay.min(MAX_DELAY_SECS))
+}
@@ -1,11 +1,12 @@
 use super::download::*;
 use super::id::TaskId;
-use super::mediatype::*;
 use super::traits::*;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 use teloxide::dispatching::dialogue::GetChatId;
 use teloxide::prelude::*;
+use crate::i18n;
+use crate::profiles::ProfileId;
 use crate::task::stats::TaskStats;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -33,21 +34,27 @@ impl TaskSimple {
         };
         Ok(obj)
     }
-    pub fn to_task_download(&self, media_type: MediaType) -> TaskDownload {
+    pub fn to_task_download(&self, profile: ProfileId) -> TaskDownload {
         TaskDownload {
             task_id: self.task_id(),
             chat_id: self.chat_id(),
-            media_type,
+            profile,
             url: None,
+            time_range: None,
+            user_id: None,
+            proxy: None,
+            language: i18n::DEFAULT_LANGUAGE.to_string(),
         }
     }
     pub fn to_task_stats(&self) -> TaskStats {
             TaskStats {
                 task_id: self.task_id(),
                 chat_id: self.chat_id(),
-                media_type: None,
+                profile: None,
                 // This unwrap is safe because TaskState::Running is not possible without URL.
                 url: None,
+                time_range: None,
+                proxy: None,
             }
     }
 }
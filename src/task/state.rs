@@ -1,16 +1,19 @@
 use super::download::*;
 use super::id::*;
-use super::mediatype::*;
 use super::simple::*;
 use super::stats::*;
+use super::timerange::TimeRange;
 use super::traits::*;
 use crate::database::*;
 use crate::misc::die;
+use crate::profiles::ProfileId;
+use crate::proxies::{PROXIES, without_credentials};
 use serde::{Deserialize, Serialize};
 use serde_type_name::type_name;
 use std::error::Error;
 use surrealdb::{Surreal, engine::remote::ws::Client as DbClient};
 use teloxide::prelude::*;
+use teloxide::types::UserId;
 use tokio_util::sync::CancellationToken;
 use url::Url;
 
@@ -47,6 +50,23 @@ impl DbRecord for TaskState {
         Ok(object_array)
     }
     #[tracing::instrument(skip(self, db), fields(task_id = %self.task_id()))]
+    async fn select_by_task_id(
+        &self,
+        db: Surreal<DbClient>,
+    ) -> Result<Vec<Self>, Box<dyn Error + Send + Sync>> {
+        let type_name = type_name(self)?;
+        trace!("{} ...", type_name);
+        let table_name = table_name(type_name);
+        // the only thing that's changed from the default trait function is that data.task_id is used instead of simply task_id
+        let query_base = format!("SELECT * FROM {table_name} WHERE data.task_id = $task_id_object");
+        let object_array: Vec<Self> = db
+            .query(&query_base)
+            .bind(("task_id_object", self.task_id()))
+            .await?
+            .take(0)?;
+        Ok(object_array)
+    }
+    #[tracing::instrument(skip(self, db), fields(task_id = %self.task_id()))]
     async fn delete_by_task_id(
         &self,
         db: Surreal<DbClient>,
@@ -124,6 +144,17 @@ impl TaskState {
         let dummy_task_state = Self::New(dummy_task_simple);
         return dummy_task_state.select_by_chat_id(db).await;
     }
+    pub async fn from_db_by_task_id(
+        task_id: TaskId,
+        db: Surreal<DbClient>,
+    ) -> Result<Vec<Self>, Box<dyn Error + Send + Sync>> {
+        let dummy_task_simple = TaskSimple {
+            task_id,
+            chat_id: ChatId(0),
+        };
+        let dummy_task_state = Self::New(dummy_task_simple);
+        return dummy_task_state.select_by_task_id(db).await;
+    }
     pub async fn from_db_all(
         db: Surreal<DbClient>,
     ) -> Result<Vec<Self>, Box<dyn Error + Send + Sync>> {
@@ -134,9 +165,9 @@ impl TaskState {
         let dummy_task_state = Self::New(dummy_task_simple);
         return dummy_task_state.from_db(db).await;
     }
-    pub async fn to_waiting_for_url(&mut self, media_type: MediaType, db: Surreal<DbClient>) {
+    pub async fn to_waiting_for_url(&mut self, profile: ProfileId, db: Surreal<DbClient>) {
         if let TaskState::New(task_simple) = self {
-            let new_state = TaskState::WaitingForUrl(task_simple.to_task_download(media_type));
+            let new_state = TaskState::WaitingForUrl(task_simple.to_task_download(profile));
             new_state.update_by_task_id(db).await.unwrap();
             *self = new_state
         } else {
@@ -147,11 +178,18 @@ impl TaskState {
     pub async fn to_running(
         &mut self,
         url: Url,
+        time_range: Option<TimeRange>,
+        user_id: Option<UserId>,
+        language: &str,
         db: Surreal<DbClient>,
         cancellation_token: CancellationToken,
     ) {
         if let TaskState::WaitingForUrl(task_download) = self {
+            task_download.set_proxy(PROXIES.for_download(&url).as_ref().map(without_credentials));
             task_download.set_url(url);
+            task_download.set_time_range(time_range);
+            task_download.set_user_id(user_id);
+            task_download.set_language(language);
             let new_state = TaskState::Running(task_download.clone());
             new_state.update_by_task_id(db).await.unwrap();
             *self = new_state;
@@ -207,6 +245,13 @@ impl TaskState {
         }
     }
 
+    pub fn get_inner_task_download_mut(&mut self) -> Option<&mut TaskDownload> {
+        match self {
+            Self::WaitingForUrl(v) | Self::Running(v) => Some(v),
+            _ => None,
+        }
+    }
+
     // pub fn get_inner_task_stats(&self) -> Option<&TaskStats> {
     //     match self {
     //         Self::Success(v) | Self::Failure(v) => Some(v),
@@ -1,16 +1,24 @@
 use super::id::TaskId;
-use super::mediatype::*;
+use super::timerange::TimeRange;
 use super::traits::*;
 use serde::{Deserialize, Serialize};
 use teloxide::prelude::*;
 use url::Url;
 
+use crate::profiles::ProfileId;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TaskStats {
     pub task_id: TaskId,
     pub chat_id: ChatId,
-    pub media_type: Option<MediaType>,
+    #[serde(default, alias = "media_type")]
+    pub profile: Option<ProfileId>,
     pub url: Option<Url>,
+    #[serde(default)]
+    pub time_range: Option<TimeRange>,
+    // Proxy the task was downloaded through, without credentials.
+    #[serde(default)]
+    pub proxy: Option<String>,
     //started_at: Utc,
     //finished_at: Utc,
     //downloaded_size;
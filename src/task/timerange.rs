@@ -0,0 +1,134 @@
+use serde::{Deserialize, Serialize};
+
+use crate::i18n;
+
+// Part of the media to download, in seconds from the beginning.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TimeRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl TimeRange {
+    // Parses ranges like 1:02:10-1:03:00, 2:10-3:00 or 130-180. Errors are in the language given.
+    pub fn parse(text: &str, language: &str) -> Result<Self, String> {
+        let error = |key: &str, text: &str| i18n::text_with(language, key, &[("text", &text)]);
+        let (start, end) = text
+            .split_once('-')
+            .ok_or_else(|| error("time_range_error.not_range", text))?;
+        let range = Self {
+            start: parse_timestamp(start).map_err(|key| error(key, start))?,
+            end: parse_timestamp(end).map_err(|key| error(key, end))?,
+        };
+        if range.end <= range.start {
+            return Err(error("time_range_error.end_before_start", text));
+        }
+        Ok(range)
+    }
+
+    // Value for yt-dlp --download-sections, the asterisk denotes a time range rather than a chapter name.
+    pub fn to_download_section(self) -> String {
+        format!("*{}-{}", self.start, self.end)
+    }
+}
+
+impl std::fmt::Display for TimeRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            format_timestamp(self.start),
+            format_timestamp(self.end)
+        )
+    }
+}
+
+// Accepts SS, MM:SS and HH:MM:SS. Only the first component may be 60 or more, e.g. 130 or 90:00.
+// Errors are catalog keys of the message.
+fn parse_timestamp(text: &str) -> Result<u32, &'static str> {
+    let components: Vec<&str> = text.trim().split(':').collect();
+    if components.len() > 3 {
+        return Err("time_range_error.not_timestamp");
+    }
+    components
+        .iter()
+        .enumerate()
+        .try_fold(0u32, |seconds, (index, component)| {
+            let value: u32 = component
+                .parse()
+                .map_err(|_| "time_range_error.not_timestamp")?;
+            if index > 0 && value >= 60 {
+                return Err("time_range_error.over_59");
+            }
+            seconds
+                .checked_mul(60)
+                .and_then(|seconds| seconds.checked_add(value))
+                .ok_or("time_range_error.too_long")
+        })
+}
+
+fn format_timestamp(seconds: u32) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{seconds:02}")
+    } else {
+        format!("{minutes}:{seconds:02}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<TimeRange, String> {
+        TimeRange::parse(text, i18n::DEFAULT_LANGUAGE)
+    }
+
+    #[test]
+    fn parses_timestamps_of_every_length() {
+        assert_eq!(
+            parse("1:02:10-1:03:00"),
+            Ok(TimeRange {
+                start: 3730,
+                end: 3780
+            })
+        );
+        assert_eq!(
+            parse("2:10-3:00"),
+            Ok(TimeRange {
+                start: 130,
+                end: 180
+            })
+        );
+        assert_eq!(
+            parse("130-180"),
+            Ok(TimeRange {
+                start: 130,
+                end: 180
+            })
+        );
+    }
+
+    #[test]
+    fn only_the_first_component_may_exceed_59() {
+        assert_eq!(parse("90:00-91:00").map(|range| range.start), Ok(5400));
+        assert!(parse("1:62-2:00").is_err());
+        assert!(parse("0:75:00-1:00:00").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_ranges() {
+        assert!(parse("1:00").is_err());
+        assert!(parse("2:00-1:00").is_err());
+        assert!(parse("1:00-1:00").is_err());
+        assert!(parse("a-b").is_err());
+        assert!(parse("1:2:3:4-1:2:3:5").is_err());
+        assert!(parse("99999999999-99999999999").is_err());
+    }
+
+    #[test]
+    fn displays_as_timestamps() {
+        assert_eq!(parse("130-3730").unwrap().to_string(), "2:10-1:02:10");
+        assert_eq!(parse("130-180").unwrap().to_download_section(), "*130-180");
+    }
+}
@@ -4,6 +4,7 @@ use std::fmt::Debug;
 use super::id::TaskId;
 use crate::database::*;
 use crate::misc::*;
+use crate::ratelimit::LimitedBot;
 use crate::trackedmessage::*;
 use surrealdb::{Surreal, engine::remote::ws::Client as DbClient};
 use teloxide::prelude::*;
@@ -27,7 +28,7 @@ where
     async fn send_and_remember_msg(
         &self,
         text: &str,
-        bot: Bot,
+        bot: LimitedBot,
         db: Surreal<DbClient>,
     ) -> Result<Vec<TrackedMessage>, Box<dyn Error + Send + Sync>> {
         let text_chunks = split_text(text);
@@ -62,7 +63,7 @@ where
         &self,
         text: &str,
         keyboard: InlineKeyboardMarkup,
-        bot: Bot,
+        bot: LimitedBot,
         db: Surreal<DbClient>,
     ) -> HandlerResult {
         debug!("Sending message with keyboard ...");
@@ -99,7 +100,11 @@ where
     }
 
     #[tracing::instrument(skip(self, bot, db), fields(task_id = %self.task_id()))]
-    async fn delete_messages_by_task_id(&self, bot: Bot, db: Surreal<DbClient>) -> HandlerResult {
+    async fn delete_messages_by_task_id(
+        &self,
+        bot: LimitedBot,
+        db: Surreal<DbClient>,
+    ) -> HandlerResult {
         let tracked_messages =
             TrackedMessage::from_db_by_task_id(self.task_id(), db.clone()).await?;
 
@@ -11,9 +11,13 @@ use tracing::{Instrument, debug, trace, warn};
 
 use crate::{
     database::DbRecord,
+    i18n,
     misc::{FolderData, sleep},
+    ratelimit::LimitedBot,
     task::{
         id::TaskId,
+        progress::{SharedProgress, SharedUploadProgress},
+        timerange::TimeRange,
         traits::{HasChatId, HasTaskId},
     },
 };
@@ -45,11 +49,22 @@ impl TrackedMessage {
         };
         dummy.select_by_task_id(db).await
     }
-    #[tracing::instrument(skip(self, cancellation_token_rx, bot))]
+    #[tracing::instrument(skip(
+        self,
+        cancellation_token_rx,
+        bot,
+        progress,
+        upload_progress,
+        time_range
+    ))]
     pub async fn directory_size_poller_and_message_updater(
         &self,
         cancellation_token_rx: CancellationToken,
-        bot: Bot,
+        bot: LimitedBot,
+        progress: SharedProgress,
+        upload_progress: SharedUploadProgress,
+        time_range: Option<TimeRange>,
+        language: String,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
         debug!("Starting poller task ...");
 
@@ -75,53 +90,66 @@ impl TrackedMessage {
                     tokio::select! {
                         _ = cancellation_token_rx.cancelled() => {
                             // Cancellation logic
-                            let update_text = "Downloading finalized.";
-                                if let Err(e) = bot
-                                    .clone()
-                                    .edit_message_text(
-                                        owned_tracked_message.chat_id(),
-                                        owned_tracked_message.message_id,
-                                        update_text,
-                                    )
-                                    .await
-                                {
-                                    warn!("Failed to update message: {}", e);
-                                }
+                            let update_text = i18n::text(&language, "downloading_finalized");
+                            if let Err(e) = bot
+                                .edit_message_text(
+                                    owned_tracked_message.chat_id(),
+                                    owned_tracked_message.message_id,
+                                    &update_text,
+                                )
+                                .await
+                            {
+                                warn!("Failed to update message: {}", e);
+                            }
                             trace!("Poller task done.");
                             break;
                         }
                         _ = interval.tick() => {
                             // Directory polling and message update logic
                             let folder_data = FolderData::from(&path_to_downloads);
+                            let progress_snapshot = progress.lock().unwrap().clone();
+                            let upload_snapshot = upload_progress.lock().unwrap().clone();
 
                             trace!(
-                                "File count: {}. Size: {}.",
-                                folder_data.file_count,
+                                "Progress: {:?}. Size: {}.",
+                                progress_snapshot,
                                 folder_data.format_bytes_to_megabytes()
                             );
-                            // Skip updating message if no files yet, informing user of 0 files is confusing
-                            if folder_data.file_count == 0 {
+                            // Skip updating message until yt-dlp reports anything, an empty progress is confusing
+                            if progress_snapshot.is_empty() && upload_snapshot.is_none() {
                                 continue;
                             }
 
-                            let update_text = format!(
-                                "Downloading... Please wait.\nFiles to send: {}.\nTotal size: {}.",
-                                folder_data.file_count,
-                                folder_data.format_bytes_to_megabytes(),
-                            );
-
-                            if update_text != previous_update_text {
+                            let mut lines = vec![i18n::text(&language, "downloading")];
+                            if let Some(time_range) = time_range {
+                                lines.push(i18n::text_with(
+                                    &language,
+                                    "clip",
+                                    &[("time_range", &time_range)],
+                                ));
+                            }
+                            lines.push(progress_snapshot.render(&language));
+                            lines.push(i18n::text_with(
+                                &language,
+                                "total_size",
+                                &[("size", &folder_data.format_bytes_to_megabytes())],
+                            ));
+                            if let Some(upload) = upload_snapshot {
+                                lines.push(upload.render(&language));
+                            }
+                            let update_text = lines.join("\n");
+
+                            // Under pressure the update is skipped, the next tick sends the latest state.
+                            if update_text != previous_update_text
+                                && let Some(request) = bot.try_edit_message_text(
+                                    owned_tracked_message.chat_id(),
+                                    owned_tracked_message.message_id,
+                                    &update_text,
+                                )
+                            {
                                 previous_update_text = update_text.clone();
 
-                                if let Err(e) = bot
-                                    .clone()
-                                    .edit_message_text(
-                                        owned_tracked_message.chat_id(),
-                                        owned_tracked_message.message_id,
-                                        &update_text,
-                                    )
-                                    .await
-                                {
+                                if let Err(e) = request.await {
                                     warn!("Failed to update message: {}", e);
                                 }
                             }